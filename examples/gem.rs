@@ -4,6 +4,7 @@ use futures::stream::StreamExt;
 use gem_rs::api::Models;
 use gem_rs::client::{GemSession, GemSessionBuilder};
 use gem_rs::init_log;
+use gem_rs::stream::StreamEvent;
 use gem_rs::types::{Blob, Context, FileManager, HarmBlockThreshold, Settings};
 
 const API_KEY: &str = "X";
@@ -74,18 +75,14 @@ async fn test_blob_stream() {
     let stream_result = session.send_blob_stream(blob, &settings).await;
 
     match stream_result {
-        Ok(mut stream) => {
-            while let Some(response) = stream.next().await {
-                match response {
-                    Ok(response) => {
-                        println!(
-                            "{}",
-                            response.get_results().get(0).unwrap_or(&"".to_string())
-                        );
-                    }
-                    Err(e) => {
-                        println!("Error: {:?}", e);
-                    }
+        Ok(stream) => {
+            let mut events = stream.events();
+            while let Some(event) = events.next().await {
+                match event {
+                    Ok(StreamEvent::TextDelta(text)) => print!("{}", text),
+                    Ok(StreamEvent::Finish { .. }) => println!(),
+                    Ok(event) => println!("{:?}", event),
+                    Err(e) => println!("Error: {:?}", e),
                 }
             }
         }
@@ -152,18 +149,14 @@ async fn test_file_stream() {
     let stream_result = session.send_file_stream(data, &settings).await;
 
     match stream_result {
-        Ok(mut stream) => {
-            while let Some(response) = stream.next().await {
-                match response {
-                    Ok(response) => {
-                        println!(
-                            "{}",
-                            response.get_results().get(0).unwrap_or(&"".to_string())
-                        );
-                    }
-                    Err(e) => {
-                        println!("Error: {:?}", e);
-                    }
+        Ok(stream) => {
+            let mut events = stream.events();
+            while let Some(event) = events.next().await {
+                match event {
+                    Ok(StreamEvent::TextDelta(text)) => print!("{}", text),
+                    Ok(StreamEvent::Finish { .. }) => println!(),
+                    Ok(event) => println!("{:?}", event),
+                    Err(e) => println!("Error: {:?}", e),
                 }
             }
         }
@@ -189,18 +182,14 @@ async fn test_stream() {
         .await;
 
     match stream_result {
-        Ok(mut stream) => {
-            while let Some(response) = stream.next().await {
-                match response {
-                    Ok(response) => {
-                        println!(
-                            "{}",
-                            response.get_results().get(0).unwrap_or(&"".to_string())
-                        );
-                    }
-                    Err(e) => {
-                        println!("Error: {:?}", e);
-                    }
+        Ok(stream) => {
+            let mut events = stream.events();
+            while let Some(event) = events.next().await {
+                match event {
+                    Ok(StreamEvent::TextDelta(text)) => print!("{}", text),
+                    Ok(StreamEvent::Finish { .. }) => println!(),
+                    Ok(event) => println!("{:?}", event),
+                    Err(e) => println!("Error: {:?}", e),
                 }
             }
         }
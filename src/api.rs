@@ -6,6 +6,9 @@
 /// functionalities into Rust projects.
 use serde::{Deserialize, Serialize};
 
+/// Default base URL of the Gemini API, to which the `ApiVersion` path is appended separately.
+pub const DEFAULT_BASE_URL: &str = "https://generativelanguage.googleapis.com";
+
 /// Base URL for generating content using the Gemini API.
 pub const GENERATE_CONTENT: &str = "https://generativelanguage.googleapis.com/v1beta/models/";
 
@@ -13,6 +16,71 @@ pub const GENERATE_CONTENT: &str = "https://generativelanguage.googleapis.com/v1
 pub const STREAM_GENERATE_CONTENT: &str =
     "https://generativelanguage.googleapis.com/v1beta/models/";
 
+/// Version of the Gemini API used in the endpoint URLs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ApiVersion {
+    /// The stable API, which lacks some features such as context caching and function calling modes
+    V1,
+    /// The beta API, which has the newest features (`v1beta1` on Vertex AI)
+    #[default]
+    V1Beta,
+}
+
+impl std::fmt::Display for ApiVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApiVersion::V1 => write!(f, "v1"),
+            ApiVersion::V1Beta => write!(f, "v1beta"),
+        }
+    }
+}
+
+/// Backend serving the Gemini models.
+///
+/// Both backends share the request and response bodies, so the same `GemSession` code runs
+/// on either of them. Vertex AI requires OAuth credentials (see `auth::BearerToken`), and
+/// does not support the file upload and embedding endpoints of the Generative Language API.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// The Generative Language API (`generativelanguage.googleapis.com`)
+    #[default]
+    GenerativeLanguage,
+    /// Vertex AI, in a Google Cloud project and location (e.g. `us-central1` or `global`)
+    VertexAi { project: String, location: String },
+}
+
+impl Backend {
+    /// Creates a Vertex AI backend for the given project and location.
+    pub fn vertex_ai(project: &str, location: &str) -> Self {
+        Backend::VertexAi {
+            project: project.to_string(),
+            location: location.to_string(),
+        }
+    }
+
+    /// Returns the base URL of the backend's endpoints.
+    pub fn default_base_url(&self) -> String {
+        match self {
+            Backend::GenerativeLanguage => DEFAULT_BASE_URL.to_string(),
+            Backend::VertexAi { location, .. } if location == "global" => {
+                "https://aiplatform.googleapis.com".to_string()
+            }
+            Backend::VertexAi { location, .. } => {
+                format!("https://{}-aiplatform.googleapis.com", location)
+            }
+        }
+    }
+
+    /// Returns the version segment of the backend's URLs, Vertex AI names its beta `v1beta1`.
+    pub(crate) fn version_path(&self, api_version: ApiVersion) -> &'static str {
+        match (self, api_version) {
+            (_, ApiVersion::V1) => "v1",
+            (Backend::GenerativeLanguage, ApiVersion::V1Beta) => "v1beta",
+            (Backend::VertexAi { .. }, ApiVersion::V1Beta) => "v1beta1",
+        }
+    }
+}
+
 /// Enum representing different Gemini API models.
 ///
 /// This enum includes various versions of Gemini models, including experimental
@@ -55,10 +123,57 @@ pub enum Models {
     /// Gemma 2 27B IT model
     #[serde(rename = "gemma-2-27b-it")]
     Gemma2_27bIt,
+
+    /// Any other model by name (e.g. `gemini-1.5-flash-002`), as returned by
+    /// `Client::list_models`. A leading `models/` is ignored.
+    #[serde(untagged)]
+    Custom(String),
+}
+
+impl Models {
+    /// Returns the maximum number of input tokens accepted by the model, or `None` for
+    /// custom models, whose limit can be fetched with `Client::get_model`.
+    pub fn input_token_limit(&self) -> Option<u32> {
+        match self {
+            Models::Gemini15ProExp0827 | Models::Gemini15Pro => Some(2_097_152),
+            Models::Gemini15FlashExp0827
+            | Models::Gemini15Flash8bExp0827
+            | Models::Gemini15Flash => Some(1_048_576),
+            Models::Gemini10Pro => Some(30_720),
+            Models::Gemma2_2bIt | Models::Gemma2_9bIt | Models::Gemma2_27bIt => Some(8_192),
+            Models::Custom(_) => None,
+        }
+    }
 }
 
 impl ToString for Models {
     fn to_string(&self) -> String {
-        serde_json::to_string(self).unwrap().replace("\"", "")
+        match self {
+            Models::Custom(name) => name.trim_start_matches("models/").to_string(),
+            _ => serde_json::to_string(self).unwrap().replace("\"", ""),
+        }
+    }
+}
+
+/// Enum representing the Gemini API embedding models.
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+pub enum EmbeddingModels {
+    /// Default text embedding model, supports elastic output dimensionality
+    #[default]
+    #[serde(rename = "text-embedding-004")]
+    TextEmbedding004,
+
+    /// Legacy embedding model
+    #[serde(rename = "embedding-001")]
+    Embedding001,
+}
+
+impl std::fmt::Display for EmbeddingModels {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}",
+            serde_json::to_string(self).unwrap().replace("\"", "")
+        )
     }
 }
@@ -0,0 +1,203 @@
+//! Authentication providers for the Gem-rs library.
+//!
+//! This module defines the `AuthProvider` trait, which adds credentials to every request
+//! sent by a `Client`, along with implementations for API keys and OAuth bearer tokens.
+
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use reqwest::RequestBuilder;
+use tokio::sync::Mutex;
+
+use crate::errors::GemError;
+
+/// Environment variables checked by `ApiKey::from_env`, in order.
+pub const API_KEY_ENV_VARS: [&str; 2] = ["GEMINI_API_KEY", "GOOGLE_API_KEY"];
+
+/// Tokens expiring within this margin are refreshed before being used.
+const REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// Adds credentials to the requests sent to the API.
+///
+/// It is called before every attempt of a request, so implementations can refresh their
+/// credentials when needed.
+#[async_trait]
+pub trait AuthProvider: Send + Sync {
+    /// Returns the request with its credentials added.
+    async fn authenticate(&self, request: RequestBuilder) -> Result<RequestBuilder, GemError>;
+}
+
+/// Sends an API key in the `x-goog-api-key` header, keeping it out of URLs and logs.
+#[derive(Clone)]
+pub struct ApiKey(String);
+
+impl ApiKey {
+    pub fn new(api_key: &str) -> Self {
+        ApiKey(api_key.to_string())
+    }
+
+    /// Loads the API key from the `GEMINI_API_KEY` or `GOOGLE_API_KEY` environment variable.
+    pub fn from_env() -> Result<Self, GemError> {
+        API_KEY_ENV_VARS
+            .iter()
+            .find_map(|name| std::env::var(name).ok().filter(|key| !key.is_empty()))
+            .map(ApiKey)
+            .ok_or_else(|| {
+                GemError::AuthError(format!(
+                    "None of the environment variables {} is set",
+                    API_KEY_ENV_VARS.join(", ")
+                ))
+            })
+    }
+}
+
+impl std::fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("ApiKey(..)")
+    }
+}
+
+#[async_trait]
+impl AuthProvider for ApiKey {
+    async fn authenticate(&self, request: RequestBuilder) -> Result<RequestBuilder, GemError> {
+        Ok(request.header("x-goog-api-key", &self.0))
+    }
+}
+
+/// An OAuth access token and its expiration.
+#[derive(Clone)]
+pub struct AccessToken {
+    token: String,
+    expires_at: Option<Instant>,
+}
+
+impl AccessToken {
+    /// Creates a token expiring after `expires_in`, or never if `None`.
+    pub fn new(token: &str, expires_in: Option<Duration>) -> Self {
+        AccessToken {
+            token: token.to_string(),
+            expires_at: expires_in.map(|expires_in| Instant::now() + expires_in),
+        }
+    }
+
+    pub fn get_token(&self) -> &str {
+        &self.token
+    }
+
+    fn is_fresh(&self) -> bool {
+        match self.expires_at {
+            Some(expires_at) => Instant::now() + REFRESH_MARGIN < expires_at,
+            None => true,
+        }
+    }
+}
+
+impl std::fmt::Debug for AccessToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AccessToken")
+            .field("expires_at", &self.expires_at)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Fetches new OAuth access tokens, e.g. from a service account flow or the metadata server.
+#[async_trait]
+pub trait TokenSource: Send + Sync {
+    async fn fetch_token(&self) -> Result<AccessToken, GemError>;
+}
+
+/// A fixed token, which is never refreshed.
+#[async_trait]
+impl TokenSource for AccessToken {
+    async fn fetch_token(&self) -> Result<AccessToken, GemError> {
+        Ok(self.clone())
+    }
+}
+
+/// Sends an OAuth access token in the `Authorization: Bearer` header, refreshing it from
+/// its `TokenSource` shortly before it expires.
+///
+/// # Example
+///
+/// ```
+/// use gem_rs::auth::{AccessToken, BearerToken};
+///
+/// // e.g. the output of `gcloud auth print-access-token`
+/// let auth = BearerToken::new(AccessToken::new("ya29.token", None));
+/// ```
+pub struct BearerToken {
+    source: Box<dyn TokenSource>,
+    token: Mutex<Option<AccessToken>>,
+}
+
+impl BearerToken {
+    pub fn new(source: impl TokenSource + 'static) -> Self {
+        BearerToken {
+            source: Box::new(source),
+            token: Mutex::new(None),
+        }
+    }
+
+    /// Returns the cached token, fetching a new one if it is missing or about to expire.
+    pub async fn token(&self) -> Result<String, GemError> {
+        let mut token = self.token.lock().await;
+        if let Some(token) = token.as_ref().filter(|token| token.is_fresh()) {
+            return Ok(token.token.clone());
+        }
+
+        log::debug!("Refreshing the access token");
+        let new_token = self.source.fetch_token().await?;
+        let value = new_token.token.clone();
+        *token = Some(new_token);
+        Ok(value)
+    }
+}
+
+#[async_trait]
+impl AuthProvider for BearerToken {
+    async fn authenticate(&self, request: RequestBuilder) -> Result<RequestBuilder, GemError> {
+        Ok(request.bearer_auth(self.token().await?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    struct CountingSource(AtomicUsize, Duration);
+
+    #[async_trait]
+    impl TokenSource for CountingSource {
+        async fn fetch_token(&self) -> Result<AccessToken, GemError> {
+            let count = self.0.fetch_add(1, Ordering::SeqCst) + 1;
+            Ok(AccessToken::new(&format!("token-{}", count), Some(self.1)))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bearer_token_refresh() {
+        let fresh = BearerToken::new(CountingSource(
+            AtomicUsize::new(0),
+            Duration::from_secs(3600),
+        ));
+        assert_eq!(fresh.token().await.unwrap(), "token-1");
+        assert_eq!(fresh.token().await.unwrap(), "token-1");
+
+        // Tokens expiring within the refresh margin are fetched again every time
+        let expiring =
+            BearerToken::new(CountingSource(AtomicUsize::new(0), Duration::from_secs(30)));
+        assert_eq!(expiring.token().await.unwrap(), "token-1");
+        assert_eq!(expiring.token().await.unwrap(), "token-2");
+
+        let request = ApiKey::new("X")
+            .authenticate(reqwest::Client::new().get("http://localhost/v1beta/models"))
+            .await
+            .unwrap()
+            .build()
+            .unwrap();
+        assert_eq!(request.headers()["x-goog-api-key"], "X");
+        assert!(request.url().query().is_none());
+    }
+}
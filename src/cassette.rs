@@ -0,0 +1,326 @@
+//! Record/replay transport for the Gem-rs library.
+//!
+//! This module defines the `Cassette`, which records the requests sent by a `Client` and
+//! their responses, streaming chunks included, to a JSON file, and serves them back without
+//! network access. It is meant for deterministic offline tests of `GemSession` flows.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use futures::StreamExt;
+use reqwest::{Client as webClient, RequestBuilder};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::errors::GemError;
+
+/// Whether a cassette records interactions or replays them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CassetteMode {
+    /// Sends the requests and saves every interaction to the cassette file
+    Record,
+    /// Serves the saved interactions back, without network access
+    Replay,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedRequest {
+    method: String,
+    url: String,
+    body: Option<Value>, // Only JSON bodies are recorded
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct RecordedResponse {
+    status: u16,
+    headers: BTreeMap<String, String>,
+    chunks: Vec<String>, // The body as it was received, chunk by chunk
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Interaction {
+    request: RecordedRequest,
+    response: RecordedResponse,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CassetteFile {
+    interactions: Vec<Interaction>,
+}
+
+#[derive(Debug)]
+struct State {
+    interactions: Vec<Interaction>,
+    used: Vec<bool>, // Interactions already served in replay mode
+}
+
+/// A JSON file of recorded interactions, shared by every clone of the handle.
+///
+/// In replay mode, each request is answered by the first unused interaction with the same
+/// method and URL, so repeated calls are served in the order they were recorded. The API
+/// key is sent in a header, so it never ends up in the cassette.
+///
+/// # Example
+///
+/// ```no_run
+/// use gem_rs::cassette::Cassette;
+/// use gem_rs::client::GemSession;
+///
+/// // Replays the cassette if it exists, otherwise records a new one
+/// let cassette = Cassette::auto("tests/cassettes/chat.json").unwrap();
+/// let session = GemSession::Builder()
+///     .cassette(cassette)
+///     .build(std::env::var("GEMINI_API_KEY").unwrap_or_default());
+/// ```
+#[derive(Debug, Clone)]
+pub struct Cassette {
+    path: Arc<PathBuf>,
+    mode: CassetteMode,
+    state: Arc<Mutex<State>>,
+}
+
+impl Cassette {
+    /// Creates a cassette recording to `path`, replacing the file once interactions complete.
+    pub fn record(path: impl AsRef<Path>) -> Self {
+        Self::with_interactions(path, CassetteMode::Record, Vec::new())
+    }
+
+    /// Loads a cassette from `path` to replay it.
+    pub fn replay(path: impl AsRef<Path>) -> Result<Self, GemError> {
+        let text = std::fs::read_to_string(path.as_ref())
+            .map_err(|e| GemError::CassetteError(format!("{}: {}", path.as_ref().display(), e)))?;
+        let file: CassetteFile = serde_json::from_str(&text).map_err(GemError::ParsingError)?;
+        Ok(Self::with_interactions(
+            path,
+            CassetteMode::Replay,
+            file.interactions,
+        ))
+    }
+
+    /// Replays the cassette at `path` if it exists, and records it otherwise.
+    pub fn auto(path: impl AsRef<Path>) -> Result<Self, GemError> {
+        if path.as_ref().exists() {
+            Self::replay(path)
+        } else {
+            Ok(Self::record(path))
+        }
+    }
+
+    fn with_interactions(
+        path: impl AsRef<Path>,
+        mode: CassetteMode,
+        interactions: Vec<Interaction>,
+    ) -> Self {
+        Cassette {
+            path: Arc::new(path.as_ref().to_path_buf()),
+            mode,
+            state: Arc::new(Mutex::new(State {
+                used: vec![false; interactions.len()],
+                interactions,
+            })),
+        }
+    }
+
+    pub fn get_mode(&self) -> CassetteMode {
+        self.mode
+    }
+
+    /// Sends a request, or answers it from the cassette in replay mode.
+    pub(crate) async fn send(
+        &self,
+        client: &webClient,
+        request: RequestBuilder,
+    ) -> Result<reqwest::Response, GemError> {
+        let request = request.build().map_err(GemError::ConnectionError)?;
+        let recorded = RecordedRequest {
+            method: request.method().to_string(),
+            url: request.url().to_string(),
+            body: request
+                .body()
+                .and_then(|body| body.as_bytes())
+                .and_then(|bytes| serde_json::from_slice(bytes).ok()),
+        };
+
+        match self.mode {
+            CassetteMode::Replay => self.replay_response(&recorded),
+            CassetteMode::Record => {
+                let response = client
+                    .execute(request)
+                    .await
+                    .map_err(GemError::ConnectionError)?;
+                self.record_response(recorded, response)
+            }
+        }
+    }
+
+    fn replay_response(&self, request: &RecordedRequest) -> Result<reqwest::Response, GemError> {
+        let mut state = self.state.lock().unwrap();
+        let State { interactions, used } = &mut *state;
+        let index = interactions
+            .iter()
+            .zip(used.iter())
+            .position(|(interaction, used)| {
+                !used
+                    && interaction.request.method == request.method
+                    && interaction.request.url == request.url
+            })
+            .ok_or_else(|| {
+                GemError::CassetteError(format!(
+                    "No recorded interaction left for {} {}",
+                    request.method, request.url
+                ))
+            })?;
+        used[index] = true;
+
+        let recorded = &interactions[index].response;
+        let chunks: Vec<Result<Vec<u8>, std::io::Error>> = recorded
+            .chunks
+            .iter()
+            .map(|chunk| Ok(chunk.clone().into_bytes()))
+            .collect();
+        build_response(
+            recorded.status,
+            &recorded.headers,
+            reqwest::Body::wrap_stream(futures::stream::iter(chunks)),
+        )
+    }
+
+    fn record_response(
+        &self,
+        request: RecordedRequest,
+        response: reqwest::Response,
+    ) -> Result<reqwest::Response, GemError> {
+        let status = response.status().as_u16();
+        let headers: BTreeMap<String, String> = response
+            .headers()
+            .iter()
+            .filter_map(|(name, value)| Some((name.to_string(), value.to_str().ok()?.to_string())))
+            .collect();
+
+        // The interaction is saved once the body is dropped, chunks are split on character
+        // boundaries so they can be stored as text
+        let mut pending = PendingInteraction {
+            cassette: self.clone(),
+            request,
+            status,
+            headers: headers.clone(),
+            chunks: ChunkRecorder::default(),
+        };
+        let body = response.bytes_stream().map(move |chunk| {
+            if let Ok(bytes) = &chunk {
+                pending.chunks.push(bytes);
+            }
+            chunk
+        });
+
+        build_response(status, &headers, reqwest::Body::wrap_stream(body))
+    }
+
+    fn save(&self, interaction: Interaction) {
+        let mut state = self.state.lock().unwrap();
+        state.interactions.push(interaction);
+        state.used.push(true);
+
+        let file = CassetteFile {
+            interactions: state.interactions.clone(),
+        };
+        let result = serde_json::to_string_pretty(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|text| std::fs::write(&*self.path, text).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log::error!("Failed to save cassette {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn build_response(
+    status: u16,
+    headers: &BTreeMap<String, String>,
+    body: reqwest::Body,
+) -> Result<reqwest::Response, GemError> {
+    let mut builder = http::Response::builder().status(status);
+    for (name, value) in headers {
+        builder = builder.header(name, value);
+    }
+    builder
+        .body(body)
+        .map(reqwest::Response::from)
+        .map_err(|e| GemError::CassetteError(e.to_string()))
+}
+
+/// An interaction whose response body is being read.
+///
+/// It is saved when the body is dropped rather than when it ends, so that responses whose
+/// body is never read, e.g. the start of a resumable upload, are recorded too.
+struct PendingInteraction {
+    cassette: Cassette,
+    request: RecordedRequest,
+    status: u16,
+    headers: BTreeMap<String, String>,
+    chunks: ChunkRecorder,
+}
+
+impl Drop for PendingInteraction {
+    fn drop(&mut self) {
+        self.cassette.save(Interaction {
+            request: self.request.clone(),
+            response: RecordedResponse {
+                status: self.status,
+                headers: std::mem::take(&mut self.headers),
+                chunks: std::mem::take(&mut self.chunks).finish(),
+            },
+        });
+    }
+}
+
+/// Collects body chunks as text, carrying incomplete characters over to the next chunk.
+#[derive(Default)]
+struct ChunkRecorder {
+    chunks: Vec<String>,
+    pending: Vec<u8>,
+}
+
+impl ChunkRecorder {
+    fn push(&mut self, bytes: &[u8]) {
+        self.pending.extend_from_slice(bytes);
+        let valid = match std::str::from_utf8(&self.pending) {
+            Ok(text) => text.len(),
+            Err(e) => e.valid_up_to(),
+        };
+        if valid > 0 {
+            let rest = self.pending.split_off(valid);
+            let text = std::mem::replace(&mut self.pending, rest);
+            self.chunks.push(String::from_utf8(text).unwrap());
+        }
+    }
+
+    fn finish(mut self) -> Vec<String> {
+        if !self.pending.is_empty() {
+            self.chunks
+                .push(String::from_utf8_lossy(&self.pending).into_owned());
+        }
+        self.chunks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chunk_recorder() {
+        let text = "مرحبا";
+        let bytes = text.as_bytes();
+
+        // Split in the middle of the first character
+        let mut recorder = ChunkRecorder::default();
+        recorder.push(&bytes[..1]);
+        recorder.push(&bytes[1..5]);
+        recorder.push(&bytes[5..]);
+
+        let chunks = recorder.finish();
+        assert_eq!(chunks.concat(), text);
+        assert!(chunks.len() >= 2);
+    }
+}
@@ -4,20 +4,44 @@
 //! sessions with the Gemini API, including support for sending messages, files, and blobs,
 //! as well as streaming responses.
 
+use std::sync::Arc;
+
 use super::types::Context;
-use error::StreamBodyError;
-use futures::Stream;
-use reqwest::{Client as webClient, StatusCode};
+use futures::StreamExt;
+use reqwest::{Client as webClient, RequestBuilder};
 use reqwest_streams::*;
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
 
-use crate::api::{Models, GENERATE_CONTENT, STREAM_GENERATE_CONTENT};
+use crate::api::{ApiVersion, Backend, EmbeddingModels, Models};
+use crate::auth::{AccessToken, ApiKey, AuthProvider, BearerToken};
+use crate::cassette::Cassette;
+use crate::embeddings::EmbeddingClient;
 use crate::errors::GemError;
-use crate::types::{Blob, Error, FileData, GenerateContentResponse, Role, Settings};
+use crate::middleware::{GenerateRequest, Middleware, MiddlewareChain};
+use crate::rate_limit::RateLimiter;
+use crate::retry::{self, RetryPolicy};
+use crate::selection::{CandidateSelector, FirstCandidate};
+use crate::stream::{
+    self, CallGuard, ChunkStream, IncompleteStreamPolicy, ResponseStream, StreamMode, StreamOptions,
+};
+use crate::tools::{self, Tool};
+use crate::types::{
+    Blob, CountTokensRequest, CountTokensResponse, Error, FileData, FunctionResponse,
+    GenerateContentResponse, ModelInfo, Role, Settings,
+};
+
+const DEFAULT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
+const DEFAULT_CONNECT_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(30);
 
 /// Represents a session with the Gemini API.
 pub struct GemSession {
     client: Client,
     context: Context,
+    check_token_limit: bool,
+    settings: Settings,
+    selector: Box<dyn CandidateSelector>,
+    stream_policy: IncompleteStreamPolicy,
 }
 
 /// Builder for creating a `GemSession` with custom configurations.
@@ -29,29 +53,52 @@ pub struct Config {
     connect_timeout: std::time::Duration,
     model: Models,
     context: Context,
+    check_token_limit: bool,
+    settings: Settings,
+    selector: Box<dyn CandidateSelector>,
+    stream_policy: IncompleteStreamPolicy,
+    retry_policy: RetryPolicy,
+    rate_limiter: Option<RateLimiter>,
+    middleware: MiddlewareChain,
+    cassette: Option<Cassette>,
+    stream_options: StreamOptions,
+    backend: Backend,
+    base_url: Option<String>,
+    api_version: ApiVersion,
 }
 
 impl GemSessionBuilder {
     /// Creates a new `GemSessionBuilder` with default settings.
     pub fn new() -> GemSessionBuilder {
         GemSessionBuilder(Config {
-            timeout: std::time::Duration::from_secs(30),
-            connect_timeout: std::time::Duration::from_secs(30),
+            timeout: DEFAULT_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
             model: Models::default(),
             context: Context::new(),
+            check_token_limit: false,
+            settings: Settings::new(),
+            selector: Box::new(FirstCandidate),
+            stream_policy: IncompleteStreamPolicy::default(),
+            retry_policy: RetryPolicy::none(),
+            rate_limiter: None,
+            middleware: MiddlewareChain::default(),
+            cassette: None,
+            stream_options: StreamOptions::new(),
+            backend: Backend::default(),
+            base_url: None,
+            api_version: ApiVersion::default(),
         })
     }
 
     /// Creates a default `GemSession` with the provided API key.
     pub fn default(api_key: String) -> GemSession {
         GemSession {
-            client: Client::new(
-                api_key,
-                Models::default(),
-                std::time::Duration::from_secs(30),
-                std::time::Duration::from_secs(30),
-            ),
+            client: Client::with_defaults(api_key),
             context: Context::new(),
+            check_token_limit: false,
+            settings: Settings::new(),
+            selector: Box::new(FirstCandidate),
+            stream_policy: IncompleteStreamPolicy::default(),
         }
     }
 
@@ -73,73 +120,483 @@ impl GemSessionBuilder {
         self
     }
 
+    /// Sets the backend serving the models. Defaults to `Backend::GenerativeLanguage`.
+    ///
+    /// Vertex AI sessions are usually built with `build_with_auth` and a refreshing
+    /// `BearerToken`, see `build` for how a plain token is sent.
+    pub fn backend(mut self, backend: Backend) -> Self {
+        self.0.backend = backend;
+        self
+    }
+
+    /// Sets the base URL of every endpoint, e.g. a local mock server or a proxy.
+    /// Defaults to the backend's URL, e.g. `https://generativelanguage.googleapis.com`.
+    pub fn base_url(mut self, base_url: &str) -> Self {
+        self.0.base_url = Some(base_url.to_string());
+        self
+    }
+
+    /// Sets the API version of every endpoint. Defaults to `ApiVersion::V1Beta`.
+    pub fn api_version(mut self, api_version: ApiVersion) -> Self {
+        self.0.api_version = api_version;
+        self
+    }
+
     /// Sets the initial context for the session.
     pub fn context(mut self, context: Context) -> Self {
         self.0.context = context;
         self
     }
 
+    /// Sets the default settings of the session.
+    ///
+    /// The settings passed to each `send_*` method are merged on top of them field by
+    /// field, and the `*_default` variants use them as-is.
+    pub fn settings(mut self, settings: Settings) -> Self {
+        self.0.settings = settings;
+        self
+    }
+
+    /// Sets the strategy used to pick which candidate is kept in the context when
+    /// `candidate_count` is greater than one. Defaults to `FirstCandidate`.
+    pub fn candidate_selector(mut self, selector: impl CandidateSelector + 'static) -> Self {
+        self.0.selector = Box::new(selector);
+        self
+    }
+
+    /// Sets what happens to the context when a streamed response fails or is dropped
+    /// before its end. Defaults to `IncompleteStreamPolicy::RemoveTurn`.
+    pub fn incomplete_stream_policy(mut self, policy: IncompleteStreamPolicy) -> Self {
+        self.0.stream_policy = policy;
+        self
+    }
+
+    /// Sets the policy used to retry requests that failed with rate limits, server
+    /// errors or transient connection errors. Requests are not retried by default.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.0.retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets a rate limiter shared with other sessions using the same API key.
+    ///
+    /// Requests wait until the limiter's budgets allow them, instead of failing with 429.
+    pub fn rate_limiter(mut self, rate_limiter: RateLimiter) -> Self {
+        self.0.rate_limiter = Some(rate_limiter);
+        self
+    }
+
+    /// Appends a middleware to the chain running around content generation calls, see
+    /// `Middleware` for the order of the hooks.
+    pub fn middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        self.0.middleware.push(Arc::new(middleware));
+        self
+    }
+
+    /// Records the session's requests to a cassette, or replays them from it without
+    /// network access.
+    pub fn cassette(mut self, cassette: Cassette) -> Self {
+        self.0.cassette = Some(cassette);
+        self
+    }
+
+    /// Sets how streamed responses are requested and parsed, e.g. `StreamOptions::sse()`
+    /// for chunks larger than the JSON array's maximum object size.
+    pub fn stream_options(mut self, stream_options: StreamOptions) -> Self {
+        self.0.stream_options = stream_options;
+        self
+    }
+
+    /// Counts the tokens of the context before every request, and fails with
+    /// `GemError::TokenLimitExceeded` instead of sending it when the model's input
+    /// limit would be exceeded. This costs an extra `countTokens` call per request.
+    pub fn check_token_limit(mut self, check_token_limit: bool) -> Self {
+        self.0.check_token_limit = check_token_limit;
+        self
+    }
+
     /// Builds a `GemSession` with the configured settings and provided API key.
+    ///
+    /// Vertex AI does not accept API keys in the `x-goog-api-key` header, so with
+    /// `Backend::VertexAi` the key is taken as an OAuth access token (e.g. the output of
+    /// `gcloud auth print-access-token`) and sent as a `BearerToken` that is never refreshed.
     pub fn build(self, api_key: String) -> GemSession {
-        GemSession::build(api_key, self.0)
+        let auth: Arc<dyn AuthProvider> = match self.0.backend {
+            Backend::GenerativeLanguage => Arc::new(ApiKey::new(&api_key)),
+            Backend::VertexAi { .. } => {
+                Arc::new(BearerToken::new(AccessToken::new(&api_key, None)))
+            }
+        };
+        GemSession::build(auth, self.0)
+    }
+
+    /// Builds a `GemSession` adding credentials to its requests with `auth`, e.g. a
+    /// `BearerToken` for service account flows.
+    pub fn build_with_auth(self, auth: impl AuthProvider + 'static) -> GemSession {
+        GemSession::build(Arc::new(auth), self.0)
+    }
+
+    /// Builds a `GemSession` with the API key of the `GEMINI_API_KEY` or `GOOGLE_API_KEY`
+    /// environment variable.
+    pub fn build_from_env(self) -> Result<GemSession, GemError> {
+        Ok(self.build_with_auth(ApiKey::from_env()?))
+    }
+
+    /// Builds a `GemSession` sharing the connection pool and configuration of `client`.
+    ///
+    /// The client's timeouts, backend, endpoints, retry policy, rate limiter and middleware
+    /// are used, so the builder's `timeout`, `connect_timeout`, `backend`, `base_url`,
+    /// `api_version`, `retry_policy`, `rate_limiter`, `middleware`, `cassette` and
+    /// `stream_options` are ignored.
+    pub fn build_with_client(self, client: &Client) -> GemSession {
+        let config = self.0;
+        GemSession {
+            client: client.with_model(config.model),
+            context: config.context,
+            check_token_limit: config.check_token_limit,
+            settings: config.settings,
+            selector: config.selector,
+            stream_policy: config.stream_policy,
+        }
     }
 }
 
-/// Internal client for making API requests to Gemini.
+/// Client for making API requests to Gemini.
+///
+/// `Client` is a cheap handle around a shared connection pool and configuration, so clones
+/// can be shared between sessions, `FileManager`, `CacheManager` and `EmbeddingClient`.
+#[derive(Clone)]
 pub struct Client {
-    client: webClient,
-    api_key: String,
+    inner: Arc<ClientInner>,
     model: Models,
 }
 
+/// Configuration shared by every clone of a `Client`.
+#[derive(Clone)]
+struct ClientInner {
+    client: webClient,
+    auth: Arc<dyn AuthProvider>,
+    backend: Backend,
+    base_url: Option<String>,
+    api_version: ApiVersion,
+    retry_policy: RetryPolicy,
+    rate_limiter: Option<RateLimiter>,
+    middleware: MiddlewareChain,
+    cassette: Option<Cassette>,
+    stream_options: StreamOptions,
+}
+
+impl std::fmt::Debug for Client {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Client")
+            .field("model", &self.model)
+            .field("backend", &self.inner.backend)
+            .field("base_url", &self.inner.base_url)
+            .field("api_version", &self.inner.api_version)
+            .field("retry_policy", &self.inner.retry_policy)
+            .field("rate_limiter", &self.inner.rate_limiter)
+            .field("middleware", &self.inner.middleware.len())
+            .field("cassette", &self.inner.cassette)
+            .field("stream_options", &self.inner.stream_options)
+            .finish_non_exhaustive()
+    }
+}
+
 impl Client {
-    /// Creates a new `Client` instance.
+    /// Creates a new `Client` instance, sending the API key in the `x-goog-api-key` header.
     pub fn new(
         api_key: String,
         model: Models,
         timeout: std::time::Duration,
         connect_timeout: std::time::Duration,
     ) -> Self {
+        Self::with_auth_provider(
+            Arc::new(ApiKey::new(&api_key)),
+            model,
+            Some(timeout),
+            connect_timeout,
+        )
+    }
+
+    /// Creates a `Client` with the default model and connection timeout, but no total
+    /// timeout, so that long uploads and downloads are not cut off.
+    pub(crate) fn without_timeout(api_key: &str) -> Self {
+        Self::with_auth_provider(
+            Arc::new(ApiKey::new(api_key)),
+            Models::default(),
+            None,
+            DEFAULT_CONNECT_TIMEOUT,
+        )
+    }
+
+    fn with_auth_provider(
+        auth: Arc<dyn AuthProvider>,
+        model: Models,
+        timeout: Option<std::time::Duration>,
+        connect_timeout: std::time::Duration,
+    ) -> Self {
+        let mut builder = webClient::builder().connect_timeout(connect_timeout);
+        if let Some(timeout) = timeout {
+            builder = builder.timeout(timeout);
+        }
+
         Client {
-            client: webClient::builder()
-                .timeout(timeout)
-                .connect_timeout(connect_timeout)
-                .build()
-                .unwrap_or(webClient::new()),
+            inner: Arc::new(ClientInner {
+                client: builder.build().unwrap_or(webClient::new()),
+                auth,
+                backend: Backend::default(),
+                base_url: None,
+                api_version: ApiVersion::default(),
+                retry_policy: RetryPolicy::none(),
+                rate_limiter: None,
+                middleware: MiddlewareChain::default(),
+                cassette: None,
+                stream_options: StreamOptions::new(),
+            }),
+            model,
+        }
+    }
+
+    /// Creates a new `Client` with the default model and timeouts.
+    pub fn with_defaults(api_key: String) -> Self {
+        Self::new(
             api_key,
+            Models::default(),
+            DEFAULT_TIMEOUT,
+            DEFAULT_CONNECT_TIMEOUT,
+        )
+    }
+
+    /// Sets the policy used to retry failed requests, requests are not retried by default.
+    ///
+    /// Clones made before this call keep their previous policy.
+    pub fn with_retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        Arc::make_mut(&mut self.inner).retry_policy = retry_policy;
+        self
+    }
+
+    /// Sets the rate limiter that every request of the client waits on.
+    ///
+    /// Clones made before this call keep their previous rate limiter.
+    pub fn with_rate_limiter(mut self, rate_limiter: Option<RateLimiter>) -> Self {
+        Arc::make_mut(&mut self.inner).rate_limiter = rate_limiter;
+        self
+    }
+
+    /// Sets the provider adding credentials to every request, replacing the API key.
+    ///
+    /// Clones made before this call keep their previous credentials.
+    pub fn with_auth(mut self, auth: impl AuthProvider + 'static) -> Self {
+        Arc::make_mut(&mut self.inner).auth = Arc::new(auth);
+        self
+    }
+
+    /// Sets the cassette recording or replaying every request of the client.
+    ///
+    /// Clones made before this call keep their previous cassette.
+    pub fn with_cassette(mut self, cassette: Option<Cassette>) -> Self {
+        Arc::make_mut(&mut self.inner).cassette = cassette;
+        self
+    }
+
+    /// Sets how streamed responses are requested and parsed.
+    ///
+    /// Clones made before this call keep their previous options.
+    pub fn with_stream_options(mut self, stream_options: StreamOptions) -> Self {
+        Arc::make_mut(&mut self.inner).stream_options = stream_options;
+        self
+    }
+
+    /// Appends a middleware to the chain running around content generation calls.
+    ///
+    /// Clones made before this call keep their previous chain.
+    pub fn with_middleware(mut self, middleware: impl Middleware + 'static) -> Self {
+        Arc::make_mut(&mut self.inner)
+            .middleware
+            .push(Arc::new(middleware));
+        self
+    }
+
+    fn with_middleware_chain(mut self, middleware: MiddlewareChain) -> Self {
+        Arc::make_mut(&mut self.inner).middleware = middleware;
+        self
+    }
+
+    /// Sets the backend serving the models, which decides how the endpoint URLs are built.
+    ///
+    /// Clones made before this call keep their previous backend.
+    pub fn with_backend(mut self, backend: Backend) -> Self {
+        Arc::make_mut(&mut self.inner).backend = backend;
+        self
+    }
+
+    /// Sets the base URL of every endpoint, e.g. a local mock server or a proxy, instead
+    /// of the backend's default.
+    ///
+    /// Clones made before this call keep their previous base URL.
+    pub fn with_base_url(mut self, base_url: &str) -> Self {
+        Arc::make_mut(&mut self.inner).base_url = Some(base_url.trim_end_matches('/').to_string());
+        self
+    }
+
+    fn with_base_url_option(self, base_url: Option<String>) -> Self {
+        match base_url {
+            Some(base_url) => self.with_base_url(&base_url),
+            None => self,
+        }
+    }
+
+    /// Returns the backend serving the models.
+    pub fn backend(&self) -> &Backend {
+        &self.inner.backend
+    }
+
+    /// Sets the API version of every endpoint.
+    ///
+    /// Clones made before this call keep their previous API version.
+    pub fn with_api_version(mut self, api_version: ApiVersion) -> Self {
+        Arc::make_mut(&mut self.inner).api_version = api_version;
+        self
+    }
+
+    fn base_url(&self) -> String {
+        match &self.inner.base_url {
+            Some(base_url) => base_url.clone(),
+            None => self.inner.backend.default_base_url(),
+        }
+    }
+
+    fn version_path(&self) -> &'static str {
+        self.inner.backend.version_path(self.inner.api_version)
+    }
+
+    /// Returns the URL of an API resource, e.g. `models/gemini-1.5-pro:generateContent`.
+    ///
+    /// On Vertex AI, the path is nested under the project and location, and `models/` under
+    /// `publishers/google/`.
+    pub(crate) fn endpoint(&self, path: &str) -> String {
+        match &self.inner.backend {
+            Backend::GenerativeLanguage => {
+                format!("{}/{}/{}", self.base_url(), self.version_path(), path)
+            }
+            Backend::VertexAi { project, location } => {
+                let path = match path.strip_prefix("models/") {
+                    Some(model) => format!("publishers/google/models/{}", model),
+                    None => path.to_string(),
+                };
+                format!(
+                    "{}/{}/projects/{}/locations/{}/{}",
+                    self.base_url(),
+                    self.version_path(),
+                    project,
+                    location,
+                    path
+                )
+            }
+        }
+    }
+
+    /// Returns the URL of a media upload resource, e.g. `files`.
+    pub(crate) fn upload_endpoint(&self, path: &str) -> String {
+        format!(
+            "{}/upload/{}/{}",
+            self.base_url(),
+            self.version_path(),
+            path
+        )
+    }
+
+    /// Returns the resource name of a model in request bodies.
+    pub(crate) fn model_resource(&self, model: &Models) -> String {
+        match &self.inner.backend {
+            Backend::GenerativeLanguage => format!("models/{}", model.to_string()),
+            Backend::VertexAi { project, location } => format!(
+                "projects/{}/locations/{}/publishers/google/models/{}",
+                project,
+                location,
+                model.to_string()
+            ),
+        }
+    }
+
+    /// Returns a handle sharing the connection pool and configuration, using another model.
+    pub fn with_model(&self, model: Models) -> Self {
+        Client {
+            inner: self.inner.clone(),
             model,
         }
     }
 
+    /// Starts a request using the client's connection pool, see `send` to authenticate it.
+    pub(crate) fn request(&self, method: reqwest::Method, url: &str) -> RequestBuilder {
+        self.inner.client.request(method, url)
+    }
+
+    /// Authenticates and sends a request once, without retries nor rate limiting.
+    pub(crate) async fn send(
+        &self,
+        request: RequestBuilder,
+    ) -> Result<reqwest::Response, GemError> {
+        let request = self.inner.auth.authenticate(request).await?;
+        self.dispatch(request).await
+    }
+
+    /// Sends a request as-is, or answers it from the cassette if there is one.
+    pub(crate) async fn dispatch(
+        &self,
+        request: RequestBuilder,
+    ) -> Result<reqwest::Response, GemError> {
+        match &self.inner.cassette {
+            Some(cassette) => cassette.send(&self.inner.client, request).await,
+            None => request.send().await.map_err(GemError::ConnectionError),
+        }
+    }
+
     /// Sends a context to the Gemini API and returns the response.
     pub(crate) async fn send_context(
         &self,
         context: &Context,
         settings: &Settings,
     ) -> Result<GenerateContentResponse, GemError> {
-        let url = format!(
-            "{}{}:generateContent",
-            GENERATE_CONTENT,
+        let url = self.endpoint(&format!(
+            "models/{}:generateContent",
             self.model.to_string()
-        );
+        ));
 
-        log::info!("URL: {}", url);
+        let mut request = GenerateRequest::new(url, context.build(settings), false);
+        let result = match self.inner.middleware.before_request(&mut request).await {
+            Ok(()) => self.send_generate_request(&request).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &result {
+            self.inner.middleware.on_error(&request, e).await;
+        }
+        result
+    }
 
-        let context = context.build(settings);
-        log::info!("Request: {:#?}", serde_json::to_string(&context).unwrap());
+    /// Sends a request prepared by the middleware and checks its response.
+    async fn send_generate_request(
+        &self,
+        request: &GenerateRequest,
+    ) -> Result<GenerateContentResponse, GemError> {
+        let body = serde_json::to_string(request.get_body()).unwrap_or_default();
+        log::debug!("URL: {}", request.get_url());
+        log::info!(
+            "Request to {}: {} bytes",
+            self.model.to_string(),
+            body.len()
+        );
+        // Bodies hold the user's content, so they are only logged at the trace level
+        log::trace!("Request: {}", body);
 
-        let response = match self
-            .client
-            .post(url)
-            .query(&[("key", &self.api_key)])
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .json(&context)
-            .send()
-            .await
-        {
-            Ok(response) => response,
-            Err(e) => return Err(GemError::ConnectionError(e)),
-        };
+        let response = self
+            .execute(
+                self.request(reqwest::Method::POST, request.get_url())
+                    .headers(request.get_headers().clone())
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .json(request.get_body()),
+            )
+            .await?;
 
         let status_code = response.status();
         let response_text = match response.text().await {
@@ -147,28 +604,39 @@ impl Client {
             Err(e) => return Err(GemError::ResponseError((e, status_code))),
         };
 
-        log::info!("Response: {}", response_text);
+        log::info!("Response: {} bytes", response_text.len());
+        log::trace!("Response: {}", response_text);
 
-        let response = match status_code {
-            StatusCode::OK => match serde_json::from_str::<GenerateContentResponse>(&response_text)
-            {
-                Ok(response) => response,
-                Err(e) => {
-                    return Err(GemError::ParsingError(e));
-                }
-            },
-            _ => match serde_json::from_str::<Error>(&response_text) {
-                Ok(error) => {
-                    return Err(GemError::GeminiAPIError(error));
-                }
-                Err(e) => return Err(GemError::ParsingError(e)),
-            },
+        let mut response = match serde_json::from_str::<GenerateContentResponse>(&response_text) {
+            Ok(response) => response,
+            Err(e) => {
+                return Err(GemError::ParsingError(e));
+            }
         };
 
+        self.inner
+            .middleware
+            .after_response(request, &mut response)
+            .await?;
+
+        if let (Some(rate_limiter), Some(usage)) =
+            (&self.inner.rate_limiter, response.get_usage_metadata())
+        {
+            rate_limiter.charge(usage.get_total_token_count().unwrap_or(0));
+        }
+
         if response.get_candidates().len() == 0 {
             return Err(GemError::EmptyApiResponse);
         }
 
+        if response
+            .get_candidates()
+            .iter()
+            .all(|candidate| candidate.is_malformed_function_call())
+        {
+            return Err(GemError::MalformedFunctionCall);
+        }
+
         let mut blocked = true;
         for candidate in response.get_candidates() {
             if candidate.get_content().is_some()
@@ -189,65 +657,313 @@ impl Client {
         Ok(response)
     }
 
-    /// Sends a context to the Gemini API and returns a stream of responses.
-    pub(crate) async fn send_context_stream(
+    /// Counts the tokens the context and settings would use in a request.
+    pub async fn count_tokens(
         &self,
         context: &Context,
         settings: &Settings,
-    ) -> Result<impl Stream<Item = Result<GenerateContentResponse, StreamBodyError>>, GemError>
-    {
-        let url = format!(
-            "{}{}:streamGenerateContent",
-            STREAM_GENERATE_CONTENT,
-            self.model.to_string()
-        );
+    ) -> Result<CountTokensResponse, GemError> {
+        let url = self.endpoint(&format!("models/{}:countTokens", self.model.to_string()));
+
+        let request = match self.inner.backend {
+            Backend::GenerativeLanguage => {
+                CountTokensRequest::new(&self.model, context.build(settings))
+            }
+            Backend::VertexAi { .. } => CountTokensRequest::vertex_ai(context.build(settings)),
+        };
+        self.post_json(url, &request).await
+    }
+
+    /// Lists the models available to the API key, along with their metadata.
+    pub async fn list_models(&self) -> Result<Vec<ModelInfo>, GemError> {
+        let mut models = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut query = vec![("pageSize", "1000".to_string())];
+            if let Some(token) = &page_token {
+                query.push(("pageToken", token.clone()));
+            }
+
+            let response: Value = self.get_json(self.endpoint("models"), &query).await?;
+
+            match response.get("models") {
+                Some(m) => match serde_json::from_value::<Vec<ModelInfo>>(m.clone()) {
+                    Ok(mut new_models) => models.append(&mut new_models),
+                    Err(e) => return Err(GemError::ParsingError(e)),
+                },
+                None => return Err(GemError::EmptyApiResponse),
+            };
+
+            page_token = response
+                .get("nextPageToken")
+                .and_then(|t| t.as_str().map(String::from));
+            if page_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(models)
+    }
 
+    /// Fetches the metadata of a model by name, with or without the `models/` prefix.
+    pub async fn get_model(&self, name: &str) -> Result<ModelInfo, GemError> {
+        let url = self.endpoint(&format!("models/{}", name.trim_start_matches("models/")));
+        self.get_json(url, &[]).await
+    }
+
+    /// Fetches the metadata of the client's model.
+    pub async fn model_info(&self) -> Result<ModelInfo, GemError> {
+        self.get_model(&self.model.to_string()).await
+    }
+
+    /// Sends a GET request to the Gemini API and parses the JSON response.
+    pub(crate) async fn get_json<T: DeserializeOwned>(
+        &self,
+        url: String,
+        query: &[(&str, String)],
+    ) -> Result<T, GemError> {
         let response = self
-            .client
-            .post(url)
-            .query(&[("key", &self.api_key)])
-            .header(reqwest::header::CONTENT_TYPE, "application/json")
-            .json(&context.build(settings))
-            .send()
-            .await;
+            .execute(self.inner.client.get(url).query(query))
+            .await?;
 
-        match response {
-            Ok(response) => {
-                let status_code = response.status();
-                match status_code {
-                    StatusCode::OK => {
-                        let json_stream =
-                            response.json_array_stream::<GenerateContentResponse>(2048);
-                        Ok(json_stream)
-                    }
-                    _ => {
-                        return Err(GemError::StreamError(format!(
-                            "Response error: {} (status code: {})",
-                            response.text().await.unwrap(),
-                            status_code
-                        )));
-                    }
+        Self::parse_json(response).await
+    }
+
+    /// Sends a JSON request to the Gemini API and parses the JSON response.
+    pub(crate) async fn post_json<B: Serialize, T: DeserializeOwned>(
+        &self,
+        url: String,
+        body: &B,
+    ) -> Result<T, GemError> {
+        let response = self
+            .execute(
+                self.inner
+                    .client
+                    .post(url)
+                    .header(reqwest::header::CONTENT_TYPE, "application/json")
+                    .json(body),
+            )
+            .await?;
+
+        Self::parse_json(response).await
+    }
+
+    /// Parses a successful JSON response.
+    async fn parse_json<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, GemError> {
+        let status_code = response.status();
+        let response_text = match response.text().await {
+            Ok(text) => text,
+            Err(e) => return Err(GemError::ResponseError((e, status_code))),
+        };
+
+        match serde_json::from_str::<T>(&response_text) {
+            Ok(response) => Ok(response),
+            Err(e) => Err(GemError::ParsingError(e)),
+        }
+    }
+
+    /// Sends a request, retrying it according to the client's `RetryPolicy`.
+    ///
+    /// Only successful responses are returned, error responses are parsed into
+    /// `GemError::GeminiAPIError` once the retries are exhausted.
+    async fn execute(&self, request: RequestBuilder) -> Result<reqwest::Response, GemError> {
+        let mut attempt = 0;
+        let mut pending = Some(request);
+        while let Some(request) = pending.take() {
+            attempt += 1;
+
+            // Requests with streaming bodies cannot be cloned, so they are sent only once
+            let current = match request.try_clone() {
+                Some(clone) if attempt < self.inner.retry_policy.get_max_attempts() => {
+                    pending = Some(clone);
+                    request
                 }
+                _ => request,
+            };
+            let is_last = pending.is_none();
+
+            if let Some(rate_limiter) = &self.inner.rate_limiter {
+                rate_limiter.acquire().await;
             }
 
-            Err(e) => {
-                return Err(GemError::ConnectionError(e));
+            // Credentials are added to each attempt, so expired tokens are refreshed
+            let current = self.inner.auth.authenticate(current).await?;
+
+            let response = match self.dispatch(current).await {
+                Ok(response) => response,
+                Err(GemError::ConnectionError(e))
+                    if !is_last && self.inner.retry_policy.retries_connection_error(&e) =>
+                {
+                    let delay = self.inner.retry_policy.delay(attempt, None);
+                    log::warn!("Connection error: {}, retrying in {:?}", e, delay);
+                    tokio::time::sleep(delay).await;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            let status_code = response.status();
+            if status_code.is_success() {
+                return Ok(response);
+            }
+
+            let retry_after = retry::retry_after(response.headers());
+            let response_text = match response.text().await {
+                Ok(text) => text,
+                Err(e) => return Err(GemError::ResponseError((e, status_code))),
+            };
+            let error = Error::from_response(&response_text);
+
+            let error_status = error.as_ref().ok().map(|error| error.get_status());
+            if !is_last
+                && self
+                    .inner
+                    .retry_policy
+                    .retries_status(status_code.as_u16(), error_status)
+            {
+                let server_delay = retry_after.or_else(|| {
+                    error
+                        .as_ref()
+                        .ok()
+                        .and_then(|error| error.get_retry_delay())
+                });
+                let delay = self.inner.retry_policy.delay(attempt, server_delay);
+                log::warn!(
+                    "Response error: {} (status code: {}), retrying in {:?}",
+                    response_text,
+                    status_code,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                continue;
             }
+
+            log::error!(
+                "Response error: {} (status code: {})",
+                response_text,
+                status_code
+            );
+            return match error {
+                Ok(error) => Err(GemError::GeminiAPIError(error)),
+                Err(e) => Err(GemError::ParsingError(e)),
+            };
+        }
+        unreachable!("the last attempt always returns")
+    }
+
+    /// Sends a context to the Gemini API and returns a stream of responses.
+    pub(crate) async fn send_context_stream(
+        &self,
+        context: &Context,
+        settings: &Settings,
+    ) -> Result<ChunkStream, GemError> {
+        let mut url = self.endpoint(&format!(
+            "models/{}:streamGenerateContent",
+            self.model.to_string()
+        ));
+        if self.inner.stream_options.get_mode() == StreamMode::Sse {
+            url.push_str("?alt=sse");
         }
+
+        let middleware = self.inner.middleware.clone();
+        let mut request = GenerateRequest::new(url, context.build(settings), true);
+        let response = match middleware.before_request(&mut request).await {
+            Ok(()) => {
+                self.execute(
+                    self.request(reqwest::Method::POST, request.get_url())
+                        .headers(request.get_headers().clone())
+                        .header(reqwest::header::CONTENT_TYPE, "application/json")
+                        .json(request.get_body()),
+                )
+                .await
+            }
+            Err(e) => Err(e),
+        };
+        let response = match response {
+            Ok(response) => response,
+            Err(e) => {
+                middleware.on_error(&request, &e).await;
+                return Err(e);
+            }
+        };
+        let request = Arc::new(request);
+
+        let options = &self.inner.stream_options;
+        let chunks: ChunkStream = match options.get_mode() {
+            StreamMode::JsonArray => Box::pin(
+                response
+                    .json_array_stream::<GenerateContentResponse>(options.get_max_object_size())
+                    .map(|chunk| chunk.map_err(|e| GemError::StreamError(e.to_string()))),
+            ),
+            StreamMode::Sse => Box::pin(stream::sse_stream(
+                Box::pin(response.bytes_stream()),
+                options.get_buffer_capacity(),
+            )),
+        };
+
+        // Every chunk holds the cumulative usage, so only the difference is charged
+        let rate_limiter = self.inner.rate_limiter.clone();
+        let mut charged = 0;
+        // Boxed so the stream stays `Unpin` for callers using `StreamExt::next` directly
+        Ok(Box::pin(
+            chunks
+                .map(move |chunk| {
+                    if let (Some(rate_limiter), Ok(chunk)) = (&rate_limiter, &chunk) {
+                        if let Some(total) = chunk
+                            .get_usage_metadata()
+                            .and_then(|usage| usage.get_total_token_count())
+                        {
+                            rate_limiter.charge(total - charged);
+                            charged = charged.max(total);
+                        }
+                    }
+                    chunk
+                })
+                .then(move |chunk| {
+                    let middleware = middleware.clone();
+                    let request = request.clone();
+                    async move {
+                        let chunk = match chunk {
+                            Ok(mut chunk) => middleware
+                                .after_response(&request, &mut chunk)
+                                .await
+                                .map(|()| chunk),
+                            Err(e) => Err(e),
+                        };
+                        if let Err(e) = &chunk {
+                            middleware.on_error(&request, e).await;
+                        }
+                        chunk
+                    }
+                }),
+        ))
     }
 }
 
 impl GemSession {
-    /// Builds a new `GemSession` with the provided API key and configuration.
-    pub(crate) fn build(api_key: String, config: Config) -> Self {
+    /// Builds a new `GemSession` with the provided credentials and configuration.
+    pub(crate) fn build(auth: Arc<dyn AuthProvider>, config: Config) -> Self {
         GemSession {
-            client: Client::new(
-                api_key,
+            client: Client::with_auth_provider(
+                auth,
                 config.model,
-                config.timeout,
+                Some(config.timeout),
                 config.connect_timeout,
-            ),
+            )
+            .with_backend(config.backend)
+            .with_base_url_option(config.base_url)
+            .with_api_version(config.api_version)
+            .with_retry_policy(config.retry_policy)
+            .with_rate_limiter(config.rate_limiter)
+            .with_middleware_chain(config.middleware)
+            .with_cassette(config.cassette)
+            .with_stream_options(config.stream_options),
             context: config.context,
+            check_token_limit: config.check_token_limit,
+            settings: config.settings,
+            selector: config.selector,
+            stream_policy: config.stream_policy,
         }
     }
 
@@ -267,42 +983,49 @@ impl GemSession {
         message: &str,
         settings: &Settings,
     ) -> Result<GenerateContentResponse, GemError> {
+        let turn = self.context.len();
         self.context.push_message(None, message.to_string());
-        let response = self.send_context(settings).await?;
-        if let Some(candidate) = response.get_candidates().first() {
-            if let Some(content) = candidate.get_content() {
-                self.context.push_message(
-                    Some(Role::Model),
-                    match content.get_text() {
-                        Some(text) => text.clone(),
-                        None => return Err(GemError::EmptyApiResponse),
-                    },
-                );
-            }
-        }
+        let mut response = self.send_context(settings, turn).await?;
+        self.push_response(&mut response)?;
         Ok(response)
     }
 
+    /// Sends a message to the Gemini API and deserializes the first candidate into `T`.
+    ///
+    /// The response MIME type is set to `application/json`, and the schema set with
+    /// `Settings::set_response_schema` (if any) is sent along with the request. If the
+    /// text cannot be deserialized, `GemError::DeserializationError` holds the raw text.
+    pub async fn send_message_typed<T: DeserializeOwned>(
+        &mut self,
+        message: &str,
+        settings: &Settings,
+    ) -> Result<T, GemError> {
+        let mut settings = self.settings.merge(settings);
+        settings.set_json_response();
+
+        let response = self.send_message(message, &settings).await?;
+        let text = match response.get_selected_result() {
+            Some(text) => text,
+            None => return Err(GemError::EmptyApiResponse),
+        };
+
+        match serde_json::from_str::<T>(&text) {
+            Ok(value) => Ok(value),
+            Err(e) => Err(GemError::DeserializationError((e, text))),
+        }
+    }
+
     /// Sends a file to the Gemini API and returns the response.
     pub async fn send_file(
         &mut self,
         file_data: FileData,
         settings: &Settings,
     ) -> Result<GenerateContentResponse, GemError> {
+        let turn = self.context.len();
         self.context.push_file(None, file_data);
 
-        let response = self.send_context(settings).await?;
-        if let Some(candidate) = response.get_candidates().first() {
-            if let Some(content) = candidate.get_content() {
-                self.context.push_message(
-                    Some(Role::Model),
-                    match content.get_text() {
-                        Some(text) => text.clone(),
-                        None => return Err(GemError::EmptyApiResponse),
-                    },
-                );
-            }
-        }
+        let mut response = self.send_context(settings, turn).await?;
+        self.push_response(&mut response)?;
         Ok(response)
     }
 
@@ -312,19 +1035,10 @@ impl GemSession {
         blob: Blob,
         settings: &Settings,
     ) -> Result<GenerateContentResponse, GemError> {
+        let turn = self.context.len();
         self.context.push_blob(None, blob);
-        let response = self.send_context(settings).await?;
-        if let Some(candidate) = response.get_candidates().first() {
-            if let Some(content) = candidate.get_content() {
-                self.context.push_message(
-                    Some(Role::Model),
-                    match content.get_text() {
-                        Some(text) => text.clone(),
-                        None => return Err(GemError::EmptyApiResponse),
-                    },
-                );
-            }
-        }
+        let mut response = self.send_context(settings, turn).await?;
+        self.push_response(&mut response)?;
         Ok(response)
     }
 
@@ -335,20 +1049,11 @@ impl GemSession {
         file_data: FileData,
         settings: &Settings,
     ) -> Result<GenerateContentResponse, GemError> {
+        let turn = self.context.len();
         self.context
             .push_message_with_file(None, message, file_data);
-        let response = self.send_context(settings).await?;
-        if let Some(candidate) = response.get_candidates().first() {
-            if let Some(content) = candidate.get_content() {
-                self.context.push_message(
-                    Some(Role::Model),
-                    match content.get_text() {
-                        Some(text) => text.clone(),
-                        None => return Err(GemError::EmptyApiResponse),
-                    },
-                );
-            }
-        }
+        let mut response = self.send_context(settings, turn).await?;
+        self.push_response(&mut response)?;
         Ok(response)
     }
 
@@ -359,31 +1064,152 @@ impl GemSession {
         blob: Blob,
         settings: &Settings,
     ) -> Result<GenerateContentResponse, GemError> {
+        let turn = self.context.len();
         self.context.push_message_with_blob(None, message, blob);
-        let response = self.send_context(settings).await?;
-        if let Some(candidate) = response.get_candidates().first() {
-            if let Some(content) = candidate.get_content() {
-                self.context.push_message(
-                    Some(Role::Model),
-                    match content.get_text() {
-                        Some(text) => text.clone(),
-                        None => return Err(GemError::EmptyApiResponse),
-                    },
-                );
+        let mut response = self.send_context(settings, turn).await?;
+        self.push_response(&mut response)?;
+        Ok(response)
+    }
+
+    /// Sends the results of the model's function calls back to the Gemini API and returns the response.
+    ///
+    /// The function calls can be read from the previous response with
+    /// `GenerateContentResponse::get_function_calls`.
+    pub async fn send_function_responses(
+        &mut self,
+        responses: Vec<FunctionResponse>,
+        settings: &Settings,
+    ) -> Result<GenerateContentResponse, GemError> {
+        let turn = self.context.len();
+        self.context.push_function_responses(responses);
+        let mut response = self.send_context(settings, turn).await?;
+        self.push_response(&mut response)?;
+        Ok(response)
+    }
+
+    /// Sends a message and runs the tool loop until the model answers with plain text.
+    ///
+    /// The declarations of `tools` are added to `settings`, every function call returned
+    /// by the model is executed against the matching tool, and the function responses are
+    /// sent back. Tools that fail, and functions without a tool, are answered with
+    /// `{"error": message}` so that the model can recover. Returns
+    /// `GemError::ToolLoopLimitReached` if the model still asks for function calls after
+    /// `max_iterations` rounds, leaving no unanswered function call in the context.
+    ///
+    /// The deadline and cancellation token of `settings` apply to the whole loop. The
+    /// context is restored to its state before `message` if the loop reaches its limit, is
+    /// cancelled or is past its deadline.
+    pub async fn run_with_tools(
+        &mut self,
+        message: &str,
+        tools: &[Box<dyn Tool>],
+        settings: &Settings,
+        max_iterations: usize,
+    ) -> Result<GenerateContentResponse, GemError> {
+        let mut settings = self.settings.merge(settings);
+        for tool in tools {
+            settings.add_function_declaration(tool.declaration());
+        }
+
+        let turn = self.context.len();
+        self.context.push_message(None, message.to_string());
+        let result = CallGuard::new(&settings)
+            .run(self.tool_loop(tools, &settings, max_iterations, turn))
+            .await;
+        self.remove_expired_turns(result, turn)
+    }
+
+    /// Internal method running the tool loop of `run_with_tools` on the pushed message,
+    /// `turn` being the length of the context before it.
+    async fn tool_loop(
+        &mut self,
+        tools: &[Box<dyn Tool>],
+        settings: &Settings,
+        max_iterations: usize,
+        turn: usize,
+    ) -> Result<GenerateContentResponse, GemError> {
+        let mut iterations = 0;
+        loop {
+            let mut response = self.request_context(settings, turn).await?;
+            self.push_response(&mut response)?;
+
+            let calls = response.get_function_calls();
+            if calls.is_empty() {
+                return Ok(response);
             }
+            if iterations >= max_iterations {
+                self.context.get_contents_mut().truncate(turn);
+                return Err(GemError::ToolLoopLimitReached(max_iterations));
+            }
+            iterations += 1;
+
+            let responses = tools::execute_calls(tools, calls).await;
+            self.context.push_function_responses(responses);
         }
-        Ok(response)
+    }
+
+    /// Appends the results of the model's function calls to the context without sending it.
+    pub fn push_function_responses(&mut self, responses: Vec<FunctionResponse>) {
+        self.context.push_function_responses(responses);
+    }
+
+    /// Returns an `EmbeddingClient` that shares the session's key and timeouts.
+    pub fn embedding_client(&self, model: EmbeddingModels) -> EmbeddingClient {
+        EmbeddingClient::new(&self.client, model)
+    }
+
+    /// Fetches the metadata of the session's model.
+    pub async fn model_info(&self) -> Result<ModelInfo, GemError> {
+        self.client.model_info().await
+    }
+
+    /// Checks the settings against the metadata of the session's model.
+    pub async fn validate_settings(&self, settings: &Settings) -> Result<(), GemError> {
+        self.client
+            .model_info()
+            .await?
+            .validate(&self.settings.merge(settings))
+    }
+
+    /// Counts the tokens the session's context would use with the given settings.
+    pub async fn count_tokens(&self, settings: &Settings) -> Result<CountTokensResponse, GemError> {
+        self.client
+            .count_tokens(&self.context, &self.settings.merge(settings))
+            .await
+    }
+
+    /// Returns the default settings of the session.
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    /// Replaces the default settings of the session.
+    pub fn set_settings(&mut self, settings: Settings) {
+        self.settings = settings;
+    }
+
+    /// Returns the conversation history of the session.
+    pub fn context(&self) -> &Context {
+        &self.context
+    }
+
+    /// Returns the session's client, which can be cloned and shared with other sessions,
+    /// `FileManager`, `CacheManager` and `EmbeddingClient`.
+    pub fn client(&self) -> &Client {
+        &self.client
     }
 
     /// Sends a message to the Gemini API and returns a stream of responses.
+    ///
+    /// The model's reply is appended to the context once the stream ends, see `ResponseStream`.
     pub async fn send_message_stream(
         &mut self,
         message: &str,
         settings: &Settings,
-    ) -> Result<impl Stream<Item = Result<GenerateContentResponse, StreamBodyError>>, GemError>
-    {
+    ) -> Result<ResponseStream<'_>, GemError> {
+        let turn = self.context.len();
         self.context.push_message(None, message.to_string());
-        self.send_context_stream(settings).await
+        self.send_context_stream(settings, turn).await
     }
 
     /// Sends a file to the Gemini API and returns a stream of responses.
@@ -391,10 +1217,10 @@ impl GemSession {
         &mut self,
         file_data: FileData,
         settings: &Settings,
-    ) -> Result<impl Stream<Item = Result<GenerateContentResponse, StreamBodyError>>, GemError>
-    {
+    ) -> Result<ResponseStream<'_>, GemError> {
+        let turn = self.context.len();
         self.context.push_file(None, file_data);
-        self.send_context_stream(settings).await
+        self.send_context_stream(settings, turn).await
     }
 
     /// Sends a blob to the Gemini API and returns a stream of responses.
@@ -402,10 +1228,10 @@ impl GemSession {
         &mut self,
         blob: Blob,
         settings: &Settings,
-    ) -> Result<impl Stream<Item = Result<GenerateContentResponse, StreamBodyError>>, GemError>
-    {
+    ) -> Result<ResponseStream<'_>, GemError> {
+        let turn = self.context.len();
         self.context.push_blob(None, blob);
-        self.send_context_stream(settings).await
+        self.send_context_stream(settings, turn).await
     }
 
     /// Sends a message with an attached file to the Gemini API and returns a stream of responses.
@@ -414,11 +1240,11 @@ impl GemSession {
         message: &str,
         file_data: FileData,
         settings: &Settings,
-    ) -> Result<impl Stream<Item = Result<GenerateContentResponse, StreamBodyError>>, GemError>
-    {
+    ) -> Result<ResponseStream<'_>, GemError> {
+        let turn = self.context.len();
         self.context
             .push_message_with_file(None, message, file_data);
-        self.send_context_stream(settings).await
+        self.send_context_stream(settings, turn).await
     }
 
     /// Sends a message with an attached blob to the Gemini API and returns a stream of responses.
@@ -427,29 +1253,234 @@ impl GemSession {
         message: &str,
         blob: Blob,
         settings: &Settings,
-    ) -> Result<impl Stream<Item = Result<GenerateContentResponse, StreamBodyError>>, GemError>
-    {
+    ) -> Result<ResponseStream<'_>, GemError> {
+        let turn = self.context.len();
         self.context.push_message_with_blob(None, message, blob);
-        self.send_context_stream(settings).await
+        self.send_context_stream(settings, turn).await
+    }
+
+    /// Sends a message using the session's default settings.
+    pub async fn send_message_default(
+        &mut self,
+        message: &str,
+    ) -> Result<GenerateContentResponse, GemError> {
+        self.send_message(message, &Settings::new()).await
+    }
+
+    /// Sends a file using the session's default settings.
+    pub async fn send_file_default(
+        &mut self,
+        file_data: FileData,
+    ) -> Result<GenerateContentResponse, GemError> {
+        self.send_file(file_data, &Settings::new()).await
+    }
+
+    /// Sends a blob using the session's default settings.
+    pub async fn send_blob_default(
+        &mut self,
+        blob: Blob,
+    ) -> Result<GenerateContentResponse, GemError> {
+        self.send_blob(blob, &Settings::new()).await
+    }
+
+    /// Sends a message with an attached file using the session's default settings.
+    pub async fn send_message_with_file_default(
+        &mut self,
+        message: &str,
+        file_data: FileData,
+    ) -> Result<GenerateContentResponse, GemError> {
+        self.send_message_with_file(message, file_data, &Settings::new())
+            .await
+    }
+
+    /// Sends a message with an attached blob using the session's default settings.
+    pub async fn send_message_with_blob_default(
+        &mut self,
+        message: &str,
+        blob: Blob,
+    ) -> Result<GenerateContentResponse, GemError> {
+        self.send_message_with_blob(message, blob, &Settings::new())
+            .await
+    }
+
+    /// Sends a message using the session's default settings and returns a stream of responses.
+    pub async fn send_message_stream_default(
+        &mut self,
+        message: &str,
+    ) -> Result<ResponseStream<'_>, GemError> {
+        self.send_message_stream(message, &Settings::new()).await
+    }
+
+    /// Sends a file using the session's default settings and returns a stream of responses.
+    pub async fn send_file_stream_default(
+        &mut self,
+        file_data: FileData,
+    ) -> Result<ResponseStream<'_>, GemError> {
+        self.send_file_stream(file_data, &Settings::new()).await
+    }
+
+    /// Sends a blob using the session's default settings and returns a stream of responses.
+    pub async fn send_blob_stream_default(
+        &mut self,
+        blob: Blob,
+    ) -> Result<ResponseStream<'_>, GemError> {
+        self.send_blob_stream(blob, &Settings::new()).await
+    }
+
+    /// Sends a message with an attached file using the session's default settings and returns a stream of responses.
+    pub async fn send_message_with_file_stream_default(
+        &mut self,
+        message: &str,
+        file_data: FileData,
+    ) -> Result<ResponseStream<'_>, GemError> {
+        self.send_message_with_file_stream(message, file_data, &Settings::new())
+            .await
+    }
+
+    /// Sends a message with an attached blob using the session's default settings and returns a stream of responses.
+    pub async fn send_message_with_blob_stream_default(
+        &mut self,
+        message: &str,
+        blob: Blob,
+    ) -> Result<ResponseStream<'_>, GemError> {
+        self.send_message_with_blob_stream(message, blob, &Settings::new())
+            .await
+    }
+
+    /// Internal method to append the selected candidate of a response to the context.
+    ///
+    /// Candidates that contain function calls are kept as-is, so that the following
+    /// function responses can be matched against them.
+    fn push_response(&mut self, response: &mut GenerateContentResponse) -> Result<(), GemError> {
+        match self.selector.select(response.get_candidates()) {
+            Some(index) if index < response.get_candidates().len() => response.set_selected(index),
+            Some(index) => log::warn!(
+                "Selected candidate {} is out of range, keeping the first one",
+                index
+            ),
+            None => {}
+        }
+        if let Some(candidate) = response.get_selected_candidate() {
+            if let Some(content) = candidate.get_content() {
+                if !content.get_function_calls().is_empty() {
+                    self.context.push_content(content.clone());
+                    return Ok(());
+                }
+                self.context.push_message(
+                    Some(Role::Model),
+                    match content.get_text() {
+                        Some(text) => text.clone(),
+                        None => return Err(GemError::EmptyApiResponse),
+                    },
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Internal method to reject a context that exceeds the model's input token limit.
+    ///
+    /// The context is truncated back to `turn` turns, so that the request can be retried
+    /// without sending it twice.
+    async fn check_token_limit(
+        &mut self,
+        settings: &Settings,
+        turn: usize,
+    ) -> Result<(), GemError> {
+        if !self.check_token_limit {
+            return Ok(());
+        }
+
+        let count = self.client.count_tokens(&self.context, settings).await?;
+        let limit = match self.client.model.input_token_limit() {
+            Some(limit) => limit,
+            None => match self.client.model_info().await?.get_input_token_limit() {
+                Some(limit) => limit,
+                None => return Ok(()),
+            },
+        };
+        if count.get_total_tokens() > limit as i32 {
+            self.context.get_contents_mut().truncate(turn);
+            return Err(GemError::TokenLimitExceeded((
+                count.get_total_tokens(),
+                limit,
+            )));
+        }
+        Ok(())
     }
 
     /// Internal method to send a context to the Gemini API.
+    ///
+    /// The context is truncated back to `turn` turns if the call is cancelled or past its
+    /// deadline, so that the request can be sent again.
     async fn send_context(
         &mut self,
         settings: &Settings,
+        turn: usize,
     ) -> Result<GenerateContentResponse, GemError> {
+        let settings = self.settings.merge(settings);
+        let result = CallGuard::new(&settings)
+            .run(self.request_context(&settings, turn))
+            .await;
+        self.remove_expired_turns(result, turn)
+    }
+
+    /// Internal method to send a context to the Gemini API with already merged settings,
+    /// without a deadline nor a cancellation token.
+    async fn request_context(
+        &mut self,
+        settings: &Settings,
+        turn: usize,
+    ) -> Result<GenerateContentResponse, GemError> {
+        self.check_token_limit(settings, turn).await?;
         self.client.send_context(&self.context, settings).await
     }
 
     /// Internal method to send a context to the Gemini API and return a stream of responses.
+    ///
+    /// The session's `IncompleteStreamPolicy` is applied if the call fails before the stream
+    /// starts, truncating the context back to `turn` turns unless the user turn is kept.
     async fn send_context_stream(
         &mut self,
         settings: &Settings,
-    ) -> Result<impl Stream<Item = Result<GenerateContentResponse, StreamBodyError>>, GemError>
-    {
-        self.client
-            .send_context_stream(&self.context, settings)
-            .await
+        turn: usize,
+    ) -> Result<ResponseStream<'_>, GemError> {
+        let settings = self.settings.merge(settings);
+        let mut guard = CallGuard::new(&settings);
+        let result = guard
+            .run(async {
+                self.check_token_limit(&settings, turn).await?;
+                self.client
+                    .send_context_stream(&self.context, &settings)
+                    .await
+            })
+            .await;
+        let stream = match result {
+            Ok(stream) => stream,
+            Err(e) => {
+                self.stream_policy.discard(&mut self.context, turn);
+                return Err(e);
+            }
+        };
+        Ok(ResponseStream::new(
+            stream,
+            &mut self.context,
+            self.stream_policy,
+            guard,
+        ))
+    }
+
+    /// Internal method to truncate the context back to `turn` turns when a call was
+    /// cancelled or past its deadline before a response was received.
+    fn remove_expired_turns<T>(
+        &mut self,
+        result: Result<T, GemError>,
+        turn: usize,
+    ) -> Result<T, GemError> {
+        if let Err(GemError::Cancelled | GemError::DeadlineExceeded(_)) = &result {
+            self.context.get_contents_mut().truncate(turn);
+        }
+        result
     }
 }
 
@@ -461,6 +1492,129 @@ mod tests {
 
     const API_KEY: &str = "X";
 
+    #[tokio::test]
+    async fn test_gem_session_replay() {
+        // Recorded against the mock server of the `testing` feature, with its address
+        // replaced by the API's
+        let cassette = Cassette::replay(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/cassettes/gem_session.json"
+        ))
+        .unwrap();
+        let mut session = GemSession::Builder()
+            .model(Models::Gemini15FlashExp0827)
+            .cassette(cassette)
+            .build(API_KEY.to_string());
+
+        let mut settings = Settings::new();
+        settings.set_all_safety_settings(HarmBlockThreshold::BlockNone);
+
+        let response = session
+            .send_message("Hello! What is your name?", &settings)
+            .await
+            .unwrap();
+        assert_eq!(response.get_results(), vec!["My name is Gemini."]);
+
+        let chunks: Vec<Result<GenerateContentResponse, GemError>> = session
+            .send_message_stream("Nice to meet you", &settings)
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        let text: String = chunks
+            .into_iter()
+            .map(|chunk| chunk.unwrap().get_results().concat())
+            .collect();
+        assert_eq!(text, "Nice to meet you!");
+        assert_eq!(
+            session.context().get_contents()[3].get_text(),
+            Some("Nice to meet you!".to_string())
+        );
+
+        let files = crate::types::FileManager::with_client(session.client());
+        let file = files
+            .add_file_from_bytes("notes.txt", b"Hello there".to_vec(), "text/plain")
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::to_value(file).unwrap()["file_uri"],
+            "https://generativelanguage.googleapis.com/v1beta/files/1"
+        );
+
+        // Every interaction was served, so another request is not in the cassette
+        assert!(matches!(
+            session.send_message("Hello again", &settings).await,
+            Err(GemError::CassetteError(_))
+        ));
+    }
+
+    #[test]
+    fn test_out_of_range_selector() {
+        let mut session = GemSession::Builder()
+            .candidate_selector(|_: &[crate::types::Candidate]| Some(5))
+            .build(API_KEY.to_string());
+        session.context.push_message(None, "Hello".to_string());
+
+        let mut response: GenerateContentResponse = serde_json::from_value(serde_json::json!({
+            "candidates": [{ "content": { "parts": [{ "text": "Hi" }], "role": "model" } }]
+        }))
+        .unwrap();
+        session.push_response(&mut response).unwrap();
+        assert_eq!(response.get_selected_result(), Some("Hi".to_string()));
+        assert_eq!(session.context.len(), 2);
+    }
+
+    #[test]
+    fn test_shared_client() {
+        let client = Client::with_defaults(API_KEY.to_string());
+        let session = GemSession::Builder()
+            .model(Models::Gemini15Pro)
+            .build_with_client(&client);
+
+        assert!(Arc::ptr_eq(&client.inner, &session.client().inner));
+        assert_eq!(session.client().model.to_string(), "gemini-1.5-pro");
+    }
+
+    #[test]
+    fn test_endpoints() {
+        let client = Client::with_defaults(API_KEY.to_string());
+        assert_eq!(
+            client.endpoint("models/gemini-1.5-pro:generateContent"),
+            format!(
+                "{}gemini-1.5-pro:generateContent",
+                crate::api::GENERATE_CONTENT
+            )
+        );
+
+        let session = GemSession::Builder()
+            .base_url("http://localhost:8080/")
+            .api_version(ApiVersion::V1)
+            .build(API_KEY.to_string());
+        assert_eq!(
+            session.client().endpoint("models"),
+            "http://localhost:8080/v1/models"
+        );
+        assert_eq!(
+            session.client().upload_endpoint("files"),
+            "http://localhost:8080/upload/v1/files"
+        );
+
+        let session = GemSession::Builder()
+            .backend(Backend::vertex_ai("my-project", "us-central1"))
+            .model(Models::Gemini15Flash)
+            .build(API_KEY.to_string());
+        assert_eq!(
+            session
+                .client()
+                .endpoint("models/gemini-1.5-flash:generateContent"),
+            "https://us-central1-aiplatform.googleapis.com/v1beta1/projects/my-project/locations/us-central1/publishers/google/models/gemini-1.5-flash:generateContent"
+        );
+        assert_eq!(
+            session.client().model_resource(&Models::Gemini15Flash),
+            "projects/my-project/locations/us-central1/publishers/google/models/gemini-1.5-flash"
+        );
+    }
+
     #[tokio::test]
     async fn test_gem_session_send_context() {
         let mut session = GemSession::Builder()
@@ -507,4 +1661,4 @@ mod tests {
         let model = Models::Gemma2_27bIt;
         assert_eq!(model.to_string(), "\"gemma-2-27b-it\"");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,241 @@
+//! Embeddings support for the Gem-rs library.
+//!
+//! This module provides the `EmbeddingClient`, which wraps the `embedContent` and
+//! `batchEmbedContents` endpoints of the Gemini API, along with the settings used to
+//! tune the generated vectors for retrieval, similarity and classification tasks.
+
+use serde::{Deserialize, Serialize};
+
+use crate::api::{Backend, EmbeddingModels};
+use crate::client::Client;
+use crate::errors::GemError;
+use crate::types::NoRoleContent;
+
+/// The task the embeddings will be used for, which lets the model optimize the vectors.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")] // To match the JSON format
+pub enum TaskType {
+    TaskTypeUnspecified, // Unset value, which will default to one of the other values
+    RetrievalQuery,      // The text is a query in a search/retrieval setting
+    RetrievalDocument,   // The text is a document from the corpus being searched
+    SemanticSimilarity,  // The text is used for semantic textual similarity
+    Classification,      // The text will be classified
+    Clustering,          // The embeddings will be used for clustering
+    QuestionAnswering,   // The text is a question in a question answering setting
+    FactVerification,    // The text is a statement to be verified
+}
+
+/// Settings applied to every text of an embedding request.
+#[derive(Debug, Clone, Default)]
+pub struct EmbeddingSettings {
+    task_type: Option<TaskType>,
+    title: Option<String>,
+    output_dimensionality: Option<u32>,
+}
+
+impl EmbeddingSettings {
+    pub fn new() -> Self {
+        EmbeddingSettings::default()
+    }
+
+    pub fn set_task_type(&mut self, task_type: TaskType) {
+        self.task_type = Some(task_type);
+    }
+
+    /// Sets the title of the text, only used with `TaskType::RetrievalDocument`.
+    pub fn set_title(&mut self, title: &str) {
+        self.title = Some(title.to_string());
+    }
+
+    /// Truncates the output embedding, only supported by newer models (e.g. `text-embedding-004`).
+    pub fn set_output_dimensionality(&mut self, output_dimensionality: u32) {
+        self.output_dimensionality = Some(output_dimensionality);
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct EmbedContentRequest {
+    model: String,                      // Required: models/{model}
+    content: NoRoleContent,             // Required: The text to embed
+    task_type: Option<TaskType>,        // Optional: Task type the embeddings will be used for
+    title: Option<String>,              // Optional: Only for RETRIEVAL_DOCUMENT
+    output_dimensionality: Option<u32>, // Optional: Reduced dimension of the output embedding
+}
+
+impl EmbedContentRequest {
+    fn new(model: &EmbeddingModels, text: &str, settings: &EmbeddingSettings) -> Self {
+        EmbedContentRequest {
+            model: format!("models/{}", model),
+            content: NoRoleContent::from_text(text),
+            task_type: settings.task_type.clone(),
+            title: settings.title.clone(),
+            output_dimensionality: settings.output_dimensionality,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct BatchEmbedContentsRequest {
+    requests: Vec<EmbedContentRequest>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct ContentEmbedding {
+    values: Vec<f32>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EmbedContentResponse {
+    embedding: ContentEmbedding,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BatchEmbedContentsResponse {
+    embeddings: Vec<ContentEmbedding>,
+}
+
+/// Client for the embedding endpoints of the Gemini API.
+#[derive(Clone)]
+pub struct EmbeddingClient {
+    client: Client,
+    model: EmbeddingModels,
+}
+
+impl EmbeddingClient {
+    /// Creates a new `EmbeddingClient` that reuses the key and timeouts of `client`.
+    pub fn new(client: &Client, model: EmbeddingModels) -> Self {
+        EmbeddingClient {
+            client: client.clone(),
+            model,
+        }
+    }
+
+    /// Embeds a single text and returns its vector.
+    pub async fn embed_content(
+        &self,
+        text: &str,
+        settings: &EmbeddingSettings,
+    ) -> Result<Vec<f32>, GemError> {
+        self.check_backend()?;
+        if text.is_empty() {
+            return Err(GemError::InvalidSettings(
+                "The text to embed is empty".to_string(),
+            ));
+        }
+
+        let url = self
+            .client
+            .endpoint(&format!("models/{}:embedContent", self.model));
+        let request = EmbedContentRequest::new(&self.model, text, settings);
+
+        let response: EmbedContentResponse = self.client.post_json(url, &request).await?;
+        Ok(response.embedding.values)
+    }
+
+    /// Embeds several texts in one request and returns their vectors in the same order.
+    ///
+    /// No request is sent for an empty list of texts.
+    pub async fn batch_embed_contents(
+        &self,
+        texts: &[&str],
+        settings: &EmbeddingSettings,
+    ) -> Result<Vec<Vec<f32>>, GemError> {
+        self.check_backend()?;
+        if texts.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let url = self
+            .client
+            .endpoint(&format!("models/{}:batchEmbedContents", self.model));
+        let request = BatchEmbedContentsRequest {
+            requests: texts
+                .iter()
+                .map(|text| EmbedContentRequest::new(&self.model, text, settings))
+                .collect(),
+        };
+
+        let response: BatchEmbedContentsResponse = self.client.post_json(url, &request).await?;
+        if response.embeddings.len() != texts.len() {
+            return Err(GemError::EmptyApiResponse);
+        }
+        Ok(response
+            .embeddings
+            .into_iter()
+            .map(|embedding| embedding.values)
+            .collect())
+    }
+
+    /// Rejects Vertex AI, which serves embeddings through its `predict` endpoint instead.
+    fn check_backend(&self) -> Result<(), GemError> {
+        if let Backend::VertexAi { .. } = self.client.backend() {
+            return Err(GemError::InvalidSettings(
+                "Vertex AI embeddings are not supported, use the Generative Language API"
+                    .to_string(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_embed_content_request() {
+        let mut settings = EmbeddingSettings::new();
+        settings.set_task_type(TaskType::RetrievalDocument);
+        settings.set_title("Gem-rs");
+        settings.set_output_dimensionality(256);
+
+        let request = BatchEmbedContentsRequest {
+            requests: vec![EmbedContentRequest::new(
+                &EmbeddingModels::TextEmbedding004,
+                "A Rust wrapper around the Gemini API",
+                &settings,
+            )],
+        };
+        let request = serde_json::to_value(request).unwrap();
+        let request = &request["requests"][0];
+        assert_eq!(request["model"], "models/text-embedding-004");
+        assert_eq!(request["taskType"], "RETRIEVAL_DOCUMENT");
+        assert_eq!(request["outputDimensionality"], 256);
+        assert_eq!(
+            request["content"]["parts"][0]["text"],
+            "A Rust wrapper around the Gemini API"
+        );
+
+        let response: BatchEmbedContentsResponse = serde_json::from_str(
+            r#"{ "embeddings": [ { "values": [0.25, -0.5] }, { "values": [1.0] } ] }"#,
+        )
+        .unwrap();
+        assert_eq!(response.embeddings[0].values, vec![0.25, -0.5]);
+    }
+
+    #[tokio::test]
+    async fn test_rejected_without_request() {
+        // Nothing listens on this port, so any request would fail to connect
+        let client = Client::with_defaults("X".to_string()).with_base_url("http://127.0.0.1:9");
+        let settings = EmbeddingSettings::new();
+
+        let embeddings = EmbeddingClient::new(&client, EmbeddingModels::TextEmbedding004);
+        assert!(embeddings
+            .batch_embed_contents(&[], &settings)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(matches!(
+            embeddings.embed_content("", &settings).await,
+            Err(GemError::InvalidSettings(_))
+        ));
+
+        let vertex = client.with_backend(Backend::vertex_ai("my-project", "us-central1"));
+        let embeddings = EmbeddingClient::new(&vertex, EmbeddingModels::TextEmbedding004);
+        assert!(matches!(
+            embeddings.embed_content("Hello", &settings).await,
+            Err(GemError::InvalidSettings(_))
+        ));
+    }
+}
@@ -33,14 +33,49 @@ pub enum GemError {
     /// Represents an error that occurred while parsing the API response.
     ParsingError(serde_json::Error),
 
+    /// Represents an error that occurred while deserializing a structured response,
+    /// including the raw text returned by the model.
+    DeserializationError((serde_json::Error, String)),
+
+    /// Indicates that the context exceeds the model's input token limit, holding the
+    /// token count and the limit.
+    TokenLimitExceeded((i32, u32)),
+
+    /// Indicates that the settings are not supported by the model.
+    InvalidSettings(String),
+
     /// Represents an error that occurred during the feedback process.
     FeedbackError(String),
 
     /// Represents an error that occurred while streaming data.
     StreamError(String),
 
+    /// Represents an error while loading or refreshing credentials.
+    AuthError(String),
+
+    /// Represents an error of the record/replay cassette, e.g. a request missing from it.
+    CassetteError(String),
+
     /// Represents an error related to file operations.
     FileError(String),
+
+    /// Represents an error related to context caching operations.
+    CacheError(String),
+
+    /// Indicates that the model generated an invalid function call.
+    MalformedFunctionCall,
+
+    /// Represents an error that occurred while executing a tool.
+    ToolError(String),
+
+    /// Indicates that the tool loop reached its iteration limit, holding the limit.
+    ToolLoopLimitReached(usize),
+
+    /// Indicates that the call was cancelled through its `CancellationToken`.
+    Cancelled,
+
+    /// Indicates that the call did not complete within its deadline, holding the deadline.
+    DeadlineExceeded(std::time::Duration),
 }
 
 impl fmt::Display for GemError {
@@ -51,13 +86,38 @@ impl fmt::Display for GemError {
             GemError::AllCandidatesBlocked => write!(f, "All candidates have a block error"),
             GemError::ConnectionError(e) => write!(f, "Connection error: {}", e),
             GemError::ParsingError(e) => write!(f, "Parsing error: {}", e),
+            GemError::DeserializationError((e, raw)) => {
+                write!(f, "Deserialization error: {} (raw response: {})", e, raw)
+            }
             GemError::GeminiAPIError(e) => write!(f, "Gemini API error: {}", e),
             GemError::ResponseError((e, status)) => {
                 write!(f, "Response error: {} (status code: {})", e, status)
             }
+            GemError::TokenLimitExceeded((count, limit)) => {
+                write!(
+                    f,
+                    "Context has {} tokens, exceeding the limit of {}",
+                    count, limit
+                )
+            }
+            GemError::InvalidSettings(e) => write!(f, "Invalid settings: {}", e),
             GemError::FeedbackError(e) => write!(f, "Feedback error: {}", e),
             GemError::StreamError(e) => write!(f, "Stream error: {}", e),
+            GemError::AuthError(e) => write!(f, "Authentication error: {}", e),
+            GemError::CassetteError(e) => write!(f, "Cassette error: {}", e),
             GemError::FileError(e) => write!(f, "File error: {}", e),
+            GemError::CacheError(e) => write!(f, "Cache error: {}", e),
+            GemError::MalformedFunctionCall => {
+                write!(f, "The model generated a malformed function call")
+            }
+            GemError::ToolError(e) => write!(f, "Tool error: {}", e),
+            GemError::ToolLoopLimitReached(limit) => {
+                write!(f, "Tool loop reached its limit of {} iterations", limit)
+            }
+            GemError::Cancelled => write!(f, "The call was cancelled"),
+            GemError::DeadlineExceeded(deadline) => {
+                write!(f, "The call did not complete within {:?}", deadline)
+            }
         }
     }
 }
@@ -6,25 +6,59 @@
 //!
 //! # Features
 //!
-//! - Streaming support for real-time interactions
+//! - Streaming support for real-time interactions, recorded in the conversation history
 //! - File and image upload capabilities
 //! - Caching mechanism for efficient file handling
+//! - Context caching through the `cachedContents` API
 //! - Comprehensive error handling and logging
+//! - Configurable retries with exponential backoff
+//! - API key header, environment variable and OAuth bearer token authentication
+//! - Generative Language API and Vertex AI backends
+//! - Request and response middleware chains
+//! - Record/replay cassettes for deterministic offline tests
+//! - In-process mock server for integration tests
+//! - Client-side rate limiting of requests and tokens per minute
 //! - Support for multiple Gemini API models
+//! - Function calling with automatic tool execution
+//! - Single and batch text embeddings
 //!
 //! # Modules
 //!
 //! - `api`: Contains API-related constants and model definitions
+//! - `auth`: Defines the authentication providers for API keys and OAuth bearer tokens
+//! - `cassette`: Defines the record/replay transport for offline tests
 //! - `client`: Provides the main client interface for interacting with the Gemini API
+//! - `embeddings`: Provides the client for the embedding endpoints
 //! - `errors`: Defines custom error types for the library
+//! - `middleware`: Defines the hooks running around content generation requests
+//! - `rate_limit`: Defines the client-side rate limiter shared between sessions
+//! - `retry`: Defines the retry policy for rate limits and transient errors
+//! - `schema`: Derives Gemini API schemas from Rust types (requires the `schema` feature)
+//! - `selection`: Defines the strategies that pick which candidate is kept in history
+//! - `stream`: Defines the streamed responses that update the session's context, and their events
+//! - `testing`: Provides a mock of the Gemini API for tests (requires the `testing` feature)
+//! - `tools`: Defines the `Tool` trait for automatic function calling
 //! - `types`: Contains various type definitions used throughout the library
 //! - `utils`: Utility functions for internal use
 
 use std::env;
 
 pub mod api;
+pub mod auth;
+pub mod cassette;
 pub mod client;
+pub mod embeddings;
 pub mod errors;
+pub mod middleware;
+pub mod rate_limit;
+pub mod retry;
+#[cfg(feature = "schema")]
+pub mod schema;
+pub mod selection;
+pub mod stream;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod tools;
 pub mod types;
 mod utils;
 
@@ -48,4 +82,4 @@ pub fn init_log() {
     pretty_env_logger::init();
     env::set_var("RUST_LOG", "info");
     log::info!("Logger initialized");
-}
\ No newline at end of file
+}
@@ -0,0 +1,216 @@
+//! Request and response middleware for the Gem-rs library.
+//!
+//! This module defines the `Middleware` trait, whose hooks run around every
+//! `generateContent` and `streamGenerateContent` call of a `Client`, e.g. to add tracing
+//! headers, redact PII, record metrics or rewrite requests.
+
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use reqwest::header::HeaderMap;
+
+use crate::errors::GemError;
+use crate::types::{GenerateContentRequest, GenerateContentResponse};
+
+/// A content generation request, as seen by the middleware before it is sent.
+#[derive(Debug, Clone)]
+pub struct GenerateRequest {
+    url: String,
+    headers: HeaderMap,
+    body: GenerateContentRequest,
+    stream: bool,
+    created: Instant,
+}
+
+impl GenerateRequest {
+    pub(crate) fn new(url: String, body: GenerateContentRequest, stream: bool) -> Self {
+        GenerateRequest {
+            url,
+            headers: HeaderMap::new(),
+            body,
+            stream,
+            created: Instant::now(),
+        }
+    }
+
+    pub fn get_url(&self) -> &str {
+        &self.url
+    }
+
+    pub fn set_url(&mut self, url: &str) {
+        self.url = url.to_string();
+    }
+
+    /// Returns the headers added to the request, the credentials are not included.
+    pub fn get_headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    pub fn get_headers_mut(&mut self) -> &mut HeaderMap {
+        &mut self.headers
+    }
+
+    pub fn get_body(&self) -> &GenerateContentRequest {
+        &self.body
+    }
+
+    pub fn get_body_mut(&mut self) -> &mut GenerateContentRequest {
+        &mut self.body
+    }
+
+    /// Returns whether the request is sent to `streamGenerateContent`.
+    pub fn is_stream(&self) -> bool {
+        self.stream
+    }
+
+    /// Returns the time elapsed since the request was created, e.g. to record latencies.
+    pub fn get_elapsed(&self) -> Duration {
+        self.created.elapsed()
+    }
+}
+
+/// Hooks running around the content generation calls of a `Client`.
+///
+/// Middleware are chained in the order they are added: `before_request` hooks run in
+/// that order, and `after_response` and `on_error` hooks in the reverse order. A hook
+/// returning an error aborts the call with it.
+///
+/// # Example
+///
+/// ```
+/// use async_trait::async_trait;
+/// use gem_rs::errors::GemError;
+/// use gem_rs::middleware::{GenerateRequest, Middleware};
+///
+/// struct Tracing;
+///
+/// #[async_trait]
+/// impl Middleware for Tracing {
+///     async fn before_request(&self, request: &mut GenerateRequest) -> Result<(), GemError> {
+///         request
+///             .get_headers_mut()
+///             .insert("x-request-id", "42".parse().unwrap());
+///         Ok(())
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait Middleware: Send + Sync {
+    /// Runs before the request is sent, and can rewrite its URL, headers and body.
+    async fn before_request(&self, _request: &mut GenerateRequest) -> Result<(), GemError> {
+        Ok(())
+    }
+
+    /// Runs after a successful response is parsed, or after each chunk of a stream.
+    async fn after_response(
+        &self,
+        _request: &GenerateRequest,
+        _response: &mut GenerateContentResponse,
+    ) -> Result<(), GemError> {
+        Ok(())
+    }
+
+    /// Runs when the request fails, including when a `before_request` hook rejects it, or
+    /// when a chunk of a stream cannot be read.
+    async fn on_error(&self, _request: &GenerateRequest, _error: &GemError) {}
+}
+
+/// Ordered list of middleware shared by the clones of a `Client`.
+#[derive(Clone, Default)]
+pub(crate) struct MiddlewareChain(Vec<Arc<dyn Middleware>>);
+
+impl MiddlewareChain {
+    pub(crate) fn push(&mut self, middleware: Arc<dyn Middleware>) {
+        self.0.push(middleware);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub(crate) async fn before_request(
+        &self,
+        request: &mut GenerateRequest,
+    ) -> Result<(), GemError> {
+        for middleware in &self.0 {
+            middleware.before_request(request).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn after_response(
+        &self,
+        request: &GenerateRequest,
+        response: &mut GenerateContentResponse,
+    ) -> Result<(), GemError> {
+        for middleware in self.0.iter().rev() {
+            middleware.after_response(request, response).await?;
+        }
+        Ok(())
+    }
+
+    pub(crate) async fn on_error(&self, request: &GenerateRequest, error: &GemError) {
+        for middleware in self.0.iter().rev() {
+            middleware.on_error(request, error).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::types::{Context, Settings};
+
+    struct Recorder(&'static str, Arc<Mutex<Vec<String>>>);
+
+    #[async_trait]
+    impl Middleware for Recorder {
+        async fn before_request(&self, request: &mut GenerateRequest) -> Result<(), GemError> {
+            self.1.lock().unwrap().push(format!("before {}", self.0));
+            request
+                .get_headers_mut()
+                .append("x-chain", self.0.parse().unwrap());
+            Ok(())
+        }
+
+        async fn after_response(
+            &self,
+            _request: &GenerateRequest,
+            _response: &mut GenerateContentResponse,
+        ) -> Result<(), GemError> {
+            self.1.lock().unwrap().push(format!("after {}", self.0));
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_middleware_chain_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut chain = MiddlewareChain::default();
+        chain.push(Arc::new(Recorder("a", log.clone())));
+        chain.push(Arc::new(Recorder("b", log.clone())));
+
+        let mut context = Context::new();
+        context.push_message(None, "Hello".to_string());
+        let mut request = GenerateRequest::new(
+            "http://localhost".to_string(),
+            context.build(&Settings::new()),
+            false,
+        );
+        chain.before_request(&mut request).await.unwrap();
+
+        let mut response: GenerateContentResponse =
+            serde_json::from_str(r#"{ "candidates": [] }"#).unwrap();
+        chain.after_response(&request, &mut response).await.unwrap();
+
+        assert_eq!(
+            *log.lock().unwrap(),
+            vec!["before a", "before b", "after b", "after a"]
+        );
+        let headers: Vec<_> = request.get_headers().get_all("x-chain").iter().collect();
+        assert_eq!(headers, vec!["a", "b"]);
+    }
+}
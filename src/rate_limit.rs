@@ -0,0 +1,160 @@
+//! Client-side rate limiting for the Gem-rs library.
+//!
+//! This module defines the `RateLimiter`, a cloneable handle that can be shared between
+//! several clients and sessions using the same API key. It holds a token bucket for the
+//! requests per minute (RPM) and a budget for the tokens per minute (TPM), which is
+//! charged with `UsageMetadata::get_total_token_count` after each response. Requests
+//! wait in line when a budget is exhausted instead of failing with 429.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// A bucket that refills continuously up to its capacity over one minute.
+#[derive(Debug)]
+struct Bucket {
+    capacity: f64,
+    available: f64,
+    last_refill: Instant,
+}
+
+impl Bucket {
+    fn new(per_minute: u32) -> Self {
+        Bucket {
+            capacity: per_minute as f64,
+            available: per_minute as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.available = (self.available + elapsed * self.capacity / 60.0).min(self.capacity);
+        self.last_refill = now;
+    }
+
+    /// Returns how long to wait until `amount` is available.
+    fn wait_time(&self, amount: f64) -> Duration {
+        if self.available >= amount {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((amount - self.available) * 60.0 / self.capacity)
+    }
+}
+
+#[derive(Debug)]
+struct State {
+    requests: Option<Bucket>,
+    tokens: Option<Bucket>,
+}
+
+/// Limits the requests and tokens sent per minute, shared by every clone of the handle.
+///
+/// # Example
+///
+/// ```
+/// use gem_rs::rate_limit::RateLimiter;
+///
+/// // 15 requests and 1M tokens per minute, shared by every session using it
+/// let limiter = RateLimiter::new(Some(15), Some(1_000_000));
+/// ```
+#[derive(Debug, Clone)]
+pub struct RateLimiter {
+    state: Arc<Mutex<State>>,
+    queue: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl RateLimiter {
+    /// Creates a limiter with optional requests per minute and tokens per minute budgets.
+    pub fn new(requests_per_minute: Option<u32>, tokens_per_minute: Option<u32>) -> Self {
+        RateLimiter {
+            state: Arc::new(Mutex::new(State {
+                requests: requests_per_minute.filter(|&rpm| rpm > 0).map(Bucket::new),
+                tokens: tokens_per_minute.filter(|&tpm| tpm > 0).map(Bucket::new),
+            })),
+            queue: Arc::new(tokio::sync::Mutex::new(())),
+        }
+    }
+
+    /// Waits until both budgets allow a new request, then takes one request from the bucket.
+    ///
+    /// Waiting callers are served in order.
+    pub async fn acquire(&self) {
+        let _turn = self.queue.lock().await;
+        loop {
+            let wait = {
+                let mut state = self.state.lock().unwrap();
+                let now = Instant::now();
+                let mut wait = Duration::ZERO;
+                if let Some(requests) = &mut state.requests {
+                    requests.refill(now);
+                    wait = wait.max(requests.wait_time(1.0));
+                }
+                if let Some(tokens) = &mut state.tokens {
+                    tokens.refill(now);
+                    // The cost is only known afterwards, so any positive budget is enough
+                    wait = wait.max(tokens.wait_time(f64::MIN_POSITIVE));
+                }
+                if wait.is_zero() {
+                    if let Some(requests) = &mut state.requests {
+                        requests.available -= 1.0;
+                    }
+                }
+                wait
+            };
+
+            if wait.is_zero() {
+                return;
+            }
+            log::info!("Rate limit reached, waiting {:?}", wait);
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Charges the tokens used by a response to the tokens per minute budget.
+    pub fn charge(&self, tokens: i32) {
+        if tokens <= 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if let Some(bucket) = &mut state.tokens {
+            bucket.refill(Instant::now());
+            bucket.available -= tokens as f64;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bucket() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(60);
+        bucket.last_refill = start;
+        bucket.available = 0.0;
+        assert_eq!(bucket.wait_time(1.0), Duration::from_secs(1));
+
+        bucket.refill(start + Duration::from_secs(30));
+        assert_eq!(bucket.available, 30.0);
+        assert_eq!(bucket.wait_time(1.0), Duration::ZERO);
+
+        bucket.refill(start + Duration::from_secs(600));
+        assert_eq!(bucket.available, 60.0);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_charge() {
+        let limiter = RateLimiter::new(Some(600), Some(6000));
+        limiter.acquire().await;
+        limiter.charge(6060);
+
+        let wait = {
+            let state = limiter.state.lock().unwrap();
+            state.tokens.as_ref().unwrap().wait_time(f64::MIN_POSITIVE)
+        };
+        assert!(wait > Duration::from_millis(500) && wait <= Duration::from_millis(600));
+    }
+}
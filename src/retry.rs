@@ -0,0 +1,228 @@
+//! Retry support for the Gem-rs library.
+//!
+//! This module defines the `RetryPolicy` used by `Client` to resend requests that failed
+//! with rate limits (429), server errors (5xx) or transient connection errors, using
+//! exponential backoff with optional jitter. Delays requested by the API, through the
+//! `Retry-After` header or a `RetryInfo` error detail, take precedence over the backoff.
+
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::header::{HeaderMap, RETRY_AFTER};
+
+/// Configures how failed requests are retried.
+///
+/// # Example
+///
+/// ```
+/// use std::time::Duration;
+/// use gem_rs::retry::RetryPolicy;
+///
+/// let policy = RetryPolicy::new()
+///     .max_attempts(5)
+///     .base_delay(Duration::from_millis(500))
+///     .max_delay(Duration::from_secs(20));
+/// ```
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    jitter: bool,
+    retry_statuses: Vec<u16>,
+    retry_error_statuses: Vec<String>,
+    retry_connection_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy::new()
+    }
+}
+
+impl RetryPolicy {
+    /// Creates a policy with 3 attempts, 1s base delay, 30s max delay and jitter, retrying
+    /// 429/500/502/503/504, `RESOURCE_EXHAUSTED`/`UNAVAILABLE`/`INTERNAL` and connection errors.
+    pub fn new() -> Self {
+        RetryPolicy {
+            max_attempts: 3,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(30),
+            jitter: true,
+            retry_statuses: vec![429, 500, 502, 503, 504],
+            retry_error_statuses: vec![
+                "RESOURCE_EXHAUSTED".to_string(),
+                "UNAVAILABLE".to_string(),
+                "INTERNAL".to_string(),
+            ],
+            retry_connection_errors: true,
+        }
+    }
+
+    /// Creates a policy that never retries, which is the default of `GemSessionBuilder`.
+    pub fn none() -> Self {
+        RetryPolicy::new().max_attempts(1)
+    }
+
+    /// Sets the total number of attempts, including the first one.
+    pub fn max_attempts(mut self, max_attempts: u32) -> Self {
+        self.max_attempts = max_attempts.max(1);
+        self
+    }
+
+    /// Sets the delay before the first retry, doubled on every following retry.
+    pub fn base_delay(mut self, base_delay: Duration) -> Self {
+        self.base_delay = base_delay;
+        self
+    }
+
+    /// Sets the upper bound of the backoff delay, also applied to delays requested by the server.
+    pub fn max_delay(mut self, max_delay: Duration) -> Self {
+        self.max_delay = max_delay;
+        self
+    }
+
+    /// Randomizes each backoff delay between half and all of its value.
+    pub fn jitter(mut self, jitter: bool) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// Sets the HTTP status codes that are retried.
+    pub fn retry_statuses(mut self, statuses: Vec<u16>) -> Self {
+        self.retry_statuses = statuses;
+        self
+    }
+
+    /// Sets the API error statuses (`Error.status`, e.g. `UNAVAILABLE`) that are retried.
+    pub fn retry_error_statuses(mut self, statuses: Vec<&str>) -> Self {
+        self.retry_error_statuses = statuses.into_iter().map(String::from).collect();
+        self
+    }
+
+    /// Sets whether connection errors and timeouts are retried.
+    pub fn retry_connection_errors(mut self, retry: bool) -> Self {
+        self.retry_connection_errors = retry;
+        self
+    }
+
+    pub fn get_max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub(crate) fn retries_connection_error(&self, error: &reqwest::Error) -> bool {
+        self.retry_connection_errors
+            && (error.is_connect() || error.is_timeout() || error.is_request())
+    }
+
+    pub(crate) fn retries_status(&self, status: u16, error_status: Option<&str>) -> bool {
+        self.retry_statuses.contains(&status)
+            || error_status.is_some_and(|error_status| {
+                self.retry_error_statuses
+                    .iter()
+                    .any(|retry| retry == error_status)
+            })
+    }
+
+    /// Returns the delay before the retry following `attempt` (starting at 1).
+    ///
+    /// A delay requested by the server is capped to `max_delay`, otherwise the exponential
+    /// backoff is capped to `max_delay` and jittered.
+    pub(crate) fn delay(&self, attempt: u32, server_delay: Option<Duration>) -> Duration {
+        if let Some(delay) = server_delay {
+            return delay.min(self.max_delay);
+        }
+
+        let factor = 2u32.saturating_pow(attempt.saturating_sub(1));
+        let delay = self.base_delay.saturating_mul(factor).min(self.max_delay);
+        if self.jitter {
+            delay.mul_f64(0.5 + fastrand::f64() * 0.5)
+        } else {
+            delay
+        }
+    }
+}
+
+/// Reads the `Retry-After` header, either in seconds or as an HTTP date (RFC 9110).
+///
+/// A date in the past yields a zero delay.
+pub(crate) fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+    if let Ok(seconds) = value.parse::<f64>() {
+        return Duration::try_from_secs_f64(seconds).ok();
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        (date.with_timezone(&Utc) - Utc::now())
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Parses a protobuf JSON duration, e.g. `"30s"` or `"1.5s"`.
+pub(crate) fn parse_duration(value: &str) -> Option<Duration> {
+    value
+        .strip_suffix('s')
+        .and_then(|seconds| seconds.parse::<f64>().ok())
+        .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::header::HeaderValue;
+
+    #[test]
+    fn test_retry_policy() {
+        let policy = RetryPolicy::new()
+            .base_delay(Duration::from_secs(1))
+            .max_delay(Duration::from_secs(5))
+            .jitter(false);
+
+        assert_eq!(policy.delay(1, None), Duration::from_secs(1));
+        assert_eq!(policy.delay(3, None), Duration::from_secs(4));
+        assert_eq!(policy.delay(10, None), Duration::from_secs(5));
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(42))),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            policy.delay(1, Some(Duration::from_secs(2))),
+            Duration::from_secs(2)
+        );
+
+        assert!(policy.retries_status(429, None));
+        assert!(policy.retries_status(400, Some("UNAVAILABLE")));
+        assert!(!policy.retries_status(400, Some("INVALID_ARGUMENT")));
+
+        let jittered = RetryPolicy::new().delay(2, None);
+        assert!(jittered >= Duration::from_secs(1) && jittered <= Duration::from_secs(2));
+
+        assert_eq!(parse_duration("1.5s"), Some(Duration::from_millis(1500)));
+        assert_eq!(parse_duration("soon"), None);
+        assert_eq!(parse_duration("1e30s"), None);
+    }
+
+    #[test]
+    fn test_retry_after() {
+        let header = |value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(RETRY_AFTER, HeaderValue::from_str(value).unwrap());
+            retry_after(&headers)
+        };
+
+        assert_eq!(header("120"), Some(Duration::from_secs(120)));
+        assert_eq!(header("-1"), None);
+        assert_eq!(header("1e30"), None);
+        assert_eq!(
+            header("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(header("soon"), None);
+
+        let date = (Utc::now() + chrono::Duration::seconds(60)).to_rfc2822();
+        let delay = header(&date).unwrap();
+        assert!(delay > Duration::from_secs(55) && delay <= Duration::from_secs(60));
+    }
+}
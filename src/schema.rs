@@ -0,0 +1,258 @@
+//! Schema generation for the Gem-rs library.
+//!
+//! This module is only available with the `schema` feature. It provides the `GeminiSchema`
+//! trait, which describes a Rust type with the OpenAPI subset understood by the Gemini API
+//! (OBJECT, ARRAY, STRING, INTEGER, NUMBER, BOOLEAN, enum, nullable and required), and the
+//! `gemini_schema!` macro, which implements it for structs and unit-only enums.
+//!
+//! The generated schemas can be used for structured output through
+//! `Settings::set_response_schema_for` and for tool parameters through
+//! `FunctionDeclaration::with_parameters`.
+
+use std::collections::{BTreeSet, HashSet, VecDeque};
+
+pub use serde_json::Value;
+use serde_json::{json, Map};
+
+/// A type that can describe itself with a Gemini API schema.
+pub trait GeminiSchema {
+    /// Returns the schema of the type.
+    fn schema() -> Value;
+
+    /// Returns `true` if the field can be omitted from an object, used to build `required`.
+    #[doc(hidden)]
+    fn is_optional() -> bool {
+        false
+    }
+}
+
+macro_rules! impl_primitive_schema {
+    ($kind:literal => $($ty:ty),*) => {
+        $(
+            impl GeminiSchema for $ty {
+                fn schema() -> Value {
+                    json!({ "type": $kind })
+                }
+            }
+        )*
+    };
+}
+
+impl_primitive_schema!("STRING" => String, str, char);
+impl_primitive_schema!("BOOLEAN" => bool);
+impl_primitive_schema!("INTEGER" => i8, i16, i32, i64, isize, u8, u16, u32, u64, usize);
+impl_primitive_schema!("NUMBER" => f32, f64);
+
+impl<T: GeminiSchema + ?Sized> GeminiSchema for &T {
+    fn schema() -> Value {
+        T::schema()
+    }
+
+    fn is_optional() -> bool {
+        T::is_optional()
+    }
+}
+
+impl<T: GeminiSchema + ?Sized> GeminiSchema for Box<T> {
+    fn schema() -> Value {
+        T::schema()
+    }
+
+    fn is_optional() -> bool {
+        T::is_optional()
+    }
+}
+
+impl<T: GeminiSchema> GeminiSchema for Option<T> {
+    fn schema() -> Value {
+        let mut schema = T::schema();
+        if let Value::Object(map) = &mut schema {
+            map.insert("nullable".to_string(), Value::Bool(true));
+        }
+        schema
+    }
+
+    fn is_optional() -> bool {
+        true
+    }
+}
+
+macro_rules! impl_array_schema {
+    ($($ty:ident),*) => {
+        $(
+            impl<T: GeminiSchema> GeminiSchema for $ty<T> {
+                fn schema() -> Value {
+                    json!({ "type": "ARRAY", "items": T::schema() })
+                }
+            }
+        )*
+    };
+}
+
+impl_array_schema!(Vec, VecDeque, HashSet, BTreeSet);
+
+impl<T: GeminiSchema> GeminiSchema for [T] {
+    fn schema() -> Value {
+        json!({ "type": "ARRAY", "items": T::schema() })
+    }
+}
+
+/// Builds an OBJECT schema from `(name, schema, optional)` triples.
+#[doc(hidden)]
+pub fn object_schema(fields: Vec<(&str, Value, bool)>) -> Value {
+    let mut properties = Map::new();
+    let mut required = Vec::new();
+    for (name, schema, optional) in fields {
+        if !optional {
+            required.push(Value::String(name.to_string()));
+        }
+        properties.insert(name.to_string(), schema);
+    }
+    json!({ "type": "OBJECT", "properties": properties, "required": required })
+}
+
+/// Builds a STRING enum schema from the variant names.
+#[doc(hidden)]
+pub fn enum_schema(variants: Vec<&str>) -> Value {
+    json!({ "type": "STRING", "format": "enum", "enum": variants })
+}
+
+/// Defines a struct or a unit-only enum and implements `GeminiSchema` for it.
+///
+/// Field and variant names are used as-is, so serde attributes that rename them
+/// (`rename`, `rename_all`) are not reflected in the schema. `Option` fields are
+/// marked as `nullable` and left out of `required`.
+///
+/// # Example
+///
+/// ```
+/// use gem_rs::gemini_schema;
+/// use gem_rs::schema::GeminiSchema;
+/// use serde::Deserialize;
+///
+/// gemini_schema! {
+///     #[derive(Debug, Deserialize)]
+///     pub enum Difficulty {
+///         Easy,
+///         Hard,
+///     }
+/// }
+///
+/// gemini_schema! {
+///     #[derive(Debug, Deserialize)]
+///     pub struct Recipe {
+///         pub name: String,
+///         pub steps: Vec<String>,
+///         pub difficulty: Difficulty,
+///         pub minutes: Option<u32>,
+///     }
+/// }
+///
+/// let schema = Recipe::schema();
+/// assert_eq!(schema["properties"]["difficulty"]["enum"][1], "Hard");
+/// assert_eq!(schema["required"].as_array().unwrap().len(), 3);
+/// ```
+#[macro_export]
+macro_rules! gemini_schema {
+    (
+        $(#[$attr:meta])*
+        $vis:vis struct $name:ident {
+            $(
+                $(#[$field_attr:meta])*
+                $field_vis:vis $field:ident : $ty:ty
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis struct $name {
+            $(
+                $(#[$field_attr])*
+                $field_vis $field: $ty,
+            )*
+        }
+
+        impl $crate::schema::GeminiSchema for $name {
+            fn schema() -> $crate::schema::Value {
+                $crate::schema::object_schema(vec![
+                    $(
+                        (
+                            stringify!($field),
+                            <$ty as $crate::schema::GeminiSchema>::schema(),
+                            <$ty as $crate::schema::GeminiSchema>::is_optional(),
+                        ),
+                    )*
+                ])
+            }
+        }
+    };
+    (
+        $(#[$attr:meta])*
+        $vis:vis enum $name:ident {
+            $(
+                $(#[$variant_attr:meta])*
+                $variant:ident
+            ),* $(,)?
+        }
+    ) => {
+        $(#[$attr])*
+        $vis enum $name {
+            $(
+                $(#[$variant_attr])*
+                $variant,
+            )*
+        }
+
+        impl $crate::schema::GeminiSchema for $name {
+            fn schema() -> $crate::schema::Value {
+                $crate::schema::enum_schema(vec![$(stringify!($variant)),*])
+            }
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    gemini_schema! {
+        #[allow(dead_code)]
+        enum Unit {
+            Celsius,
+            Fahrenheit,
+        }
+    }
+
+    gemini_schema! {
+        #[allow(dead_code)]
+        struct Forecast {
+            city: String,
+            days: Vec<Option<f32>>,
+            unit: Unit,
+            note: Option<String>,
+        }
+    }
+
+    #[test]
+    fn test_struct_schema() {
+        assert_eq!(
+            Forecast::schema(),
+            json!({
+                "type": "OBJECT",
+                "properties": {
+                    "city": { "type": "STRING" },
+                    "days": {
+                        "type": "ARRAY",
+                        "items": { "type": "NUMBER", "nullable": true }
+                    },
+                    "unit": {
+                        "type": "STRING",
+                        "format": "enum",
+                        "enum": ["Celsius", "Fahrenheit"]
+                    },
+                    "note": { "type": "STRING", "nullable": true }
+                },
+                "required": ["city", "days", "unit"]
+            })
+        );
+    }
+}
@@ -0,0 +1,120 @@
+//! Candidate selection for the Gem-rs library.
+//!
+//! When `candidate_count` is greater than one, the Gemini API returns several candidates
+//! for the same turn, but only one of them can be kept in the session's `Context`. This
+//! module defines the `CandidateSelector` trait used by `GemSession` to pick it, along
+//! with the built-in strategies. Closures of type `Fn(&[Candidate]) -> Option<usize>`
+//! can be used as selectors too.
+
+use crate::types::Candidate;
+
+/// Picks the candidate that is appended to the session's context.
+pub trait CandidateSelector: Send + Sync {
+    /// Returns the index of the selected candidate, or `None` to fall back to the first one.
+    /// An index out of range also falls back to the first candidate.
+    fn select(&self, candidates: &[Candidate]) -> Option<usize>;
+}
+
+impl<F> CandidateSelector for F
+where
+    F: Fn(&[Candidate]) -> Option<usize> + Send + Sync,
+{
+    fn select(&self, candidates: &[Candidate]) -> Option<usize> {
+        self(candidates)
+    }
+}
+
+/// Selects the first candidate, which is the default behavior.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FirstCandidate;
+
+impl CandidateSelector for FirstCandidate {
+    fn select(&self, candidates: &[Candidate]) -> Option<usize> {
+        if candidates.is_empty() {
+            None
+        } else {
+            Some(0)
+        }
+    }
+}
+
+/// Selects the candidate with the longest text.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LongestCandidate;
+
+impl CandidateSelector for LongestCandidate {
+    fn select(&self, candidates: &[Candidate]) -> Option<usize> {
+        candidates
+            .iter()
+            .enumerate()
+            .filter_map(|(index, candidate)| {
+                candidate
+                    .get_text()
+                    .map(|text| (index, text.chars().count()))
+            })
+            .max_by(|a, b| a.1.cmp(&b.1).then(b.0.cmp(&a.0)))
+            .map(|(index, _)| index)
+    }
+}
+
+/// Selects the candidate with the highest average log probability.
+///
+/// Falls back to the first candidate when the API does not return `avgLogprobs`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HighestAvgLogprobs;
+
+impl CandidateSelector for HighestAvgLogprobs {
+    fn select(&self, candidates: &[Candidate]) -> Option<usize> {
+        candidates
+            .iter()
+            .enumerate()
+            .filter(|(_, candidate)| candidate.get_text().is_some())
+            .filter_map(|(index, candidate)| {
+                candidate
+                    .get_avg_logprobs()
+                    .map(|logprobs| (index, logprobs))
+            })
+            .max_by(|a, b| a.1.total_cmp(&b.1).then(b.0.cmp(&a.0)))
+            .map(|(index, _)| index)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn candidates() -> Vec<Candidate> {
+        serde_json::from_str(
+            r#"[
+                {
+                    "content": { "parts": [{ "text": "Short" }], "role": "model" },
+                    "avgLogprobs": -0.9,
+                    "index": 0
+                },
+                {
+                    "content": { "parts": [{ "text": "A longer answer" }], "role": "model" },
+                    "avgLogprobs": -0.4,
+                    "index": 1
+                },
+                {
+                    "content": { "parts": [{ "text": "Medium one" }], "role": "model" },
+                    "avgLogprobs": -0.1,
+                    "index": 2
+                }
+            ]"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_selectors() {
+        let candidates = candidates();
+        assert_eq!(FirstCandidate.select(&candidates), Some(0));
+        assert_eq!(LongestCandidate.select(&candidates), Some(1));
+        assert_eq!(HighestAvgLogprobs.select(&candidates), Some(2));
+
+        let last = |candidates: &[Candidate]| candidates.len().checked_sub(1);
+        assert_eq!(last.select(&candidates), Some(2));
+        assert_eq!(LongestCandidate.select(&[]), None);
+    }
+}
@@ -0,0 +1,918 @@
+//! Streaming responses that record the model's reply in the session's context.
+//!
+//! This module defines `ResponseStream`, returned by the `send_*_stream` methods of
+//! `GemSession`. It yields the chunks of the response as they arrive, collects their
+//! parts, and appends the full model turn to the context once the stream ends. When the
+//! stream fails or is dropped before its end, the `IncompleteStreamPolicy` of the session
+//! decides what is kept.
+//!
+//! `ResponseStream::events` turns the chunks into `StreamEvent`s, i.e. text deltas,
+//! function calls, safety blocks and a final event with the finish reason and usage.
+//!
+//! The chunks are read from one JSON array by default, or from Server-Sent Events
+//! (`alt=sse`) with `StreamOptions::sse`, which has no limit on the size of a chunk.
+//!
+//! The deadline and cancellation token set in `Settings` also bound the stream: once
+//! either expires, the stream yields `GemError::Cancelled` or `GemError::DeadlineExceeded`,
+//! closes the connection and applies the `IncompleteStreamPolicy`.
+//!
+//! `ResponseStream::write_to`, `send_to` and `broadcast_to` forward the text deltas to an
+//! `AsyncWrite`, an mpsc channel or a broadcast channel, and return the full response.
+
+use std::collections::VecDeque;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use futures::{stream, Stream, StreamExt};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::{broadcast, mpsc};
+use tokio::time::Sleep;
+use tokio_util::sync::WaitForCancellationFutureOwned;
+
+use crate::errors::GemError;
+use crate::types::{
+    BlockReason, Content, Context, FinishReason, FunctionCall, GenerateContentResponse, Part,
+    PartData, Role, SafetyRating, Settings, UsageMetadata,
+};
+
+/// The default maximum size in bytes of a chunk in `StreamMode::JsonArray`.
+pub const DEFAULT_MAX_OBJECT_SIZE: usize = 2048;
+
+/// The default initial capacity in bytes of the line buffer in `StreamMode::Sse`.
+pub const DEFAULT_BUFFER_CAPACITY: usize = 8192;
+
+/// How the chunks of a streamed response are read.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StreamMode {
+    /// The response is one JSON array, parsed object by object. Objects larger than
+    /// the maximum object size fail the stream.
+    #[default]
+    JsonArray,
+    /// The response is requested as Server-Sent Events (`alt=sse`) and parsed line by
+    /// line, without any limit on the size of a chunk.
+    Sse,
+}
+
+/// Options for parsing streamed responses.
+///
+/// # Example
+///
+/// ```
+/// use gem_rs::client::GemSession;
+/// use gem_rs::stream::StreamOptions;
+///
+/// let session = GemSession::Builder()
+///     .stream_options(StreamOptions::sse().buffer_capacity(64 * 1024))
+///     .build("API_KEY".to_string());
+/// ```
+#[derive(Debug, Clone)]
+pub struct StreamOptions {
+    mode: StreamMode,
+    max_object_size: usize,
+    buffer_capacity: usize,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        StreamOptions::new()
+    }
+}
+
+impl StreamOptions {
+    /// Creates options reading one JSON array with objects of at most 2048 bytes.
+    pub fn new() -> Self {
+        StreamOptions {
+            mode: StreamMode::JsonArray,
+            max_object_size: DEFAULT_MAX_OBJECT_SIZE,
+            buffer_capacity: DEFAULT_BUFFER_CAPACITY,
+        }
+    }
+
+    /// Creates options reading Server-Sent Events.
+    pub fn sse() -> Self {
+        StreamOptions::new().mode(StreamMode::Sse)
+    }
+
+    pub fn mode(mut self, mode: StreamMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Sets the maximum size in bytes of a chunk in `StreamMode::JsonArray`.
+    pub fn max_object_size(mut self, max_object_size: usize) -> Self {
+        self.max_object_size = max_object_size;
+        self
+    }
+
+    /// Sets the initial capacity in bytes of the line buffer in `StreamMode::Sse`, which
+    /// grows as needed.
+    pub fn buffer_capacity(mut self, buffer_capacity: usize) -> Self {
+        self.buffer_capacity = buffer_capacity;
+        self
+    }
+
+    pub fn get_mode(&self) -> StreamMode {
+        self.mode
+    }
+
+    pub fn get_max_object_size(&self) -> usize {
+        self.max_object_size
+    }
+
+    pub fn get_buffer_capacity(&self) -> usize {
+        self.buffer_capacity
+    }
+}
+
+/// Incremental parser of Server-Sent Events holding `GenerateContentResponse`s.
+struct SseParser {
+    buffer: Vec<u8>,
+    data: String,
+}
+
+impl SseParser {
+    fn new(capacity: usize) -> Self {
+        SseParser {
+            buffer: Vec::with_capacity(capacity),
+            data: String::new(),
+        }
+    }
+
+    /// Parses the complete lines of `bytes`, keeping the last incomplete one.
+    fn push(&mut self, bytes: &[u8]) -> Vec<Result<GenerateContentResponse, GemError>> {
+        self.buffer.extend_from_slice(bytes);
+        let mut events = Vec::new();
+        let mut start = 0;
+        while let Some(end) = self.buffer[start..].iter().position(|b| *b == b'\n') {
+            let line = String::from_utf8_lossy(&self.buffer[start..start + end]).into_owned();
+            start += end + 1;
+            if let Some(event) = self.line(line.trim_end_matches('\r')) {
+                events.push(event);
+            }
+        }
+        self.buffer.drain(..start);
+        events
+    }
+
+    /// Parses what is left once the response ends.
+    fn finish(&mut self) -> Vec<Result<GenerateContentResponse, GemError>> {
+        let mut events = self.push(b"\n");
+        events.extend(self.line(""));
+        events
+    }
+
+    /// Handles one line, dispatching the event's data on an empty line.
+    fn line(&mut self, line: &str) -> Option<Result<GenerateContentResponse, GemError>> {
+        if line.is_empty() {
+            if self.data.is_empty() {
+                return None;
+            }
+            let data = std::mem::take(&mut self.data);
+            return Some(serde_json::from_str(&data).map_err(GemError::ParsingError));
+        }
+        // Comments and the `event`, `id` and `retry` fields are not used by the API
+        if let Some(data) = line.strip_prefix("data:") {
+            if !self.data.is_empty() {
+                self.data.push('\n');
+            }
+            self.data.push_str(data.strip_prefix(' ').unwrap_or(data));
+        }
+        None
+    }
+}
+
+/// Parses a response body of Server-Sent Events into its chunks.
+pub(crate) fn sse_stream<S, B>(
+    bytes: S,
+    buffer_capacity: usize,
+) -> impl Stream<Item = Result<GenerateContentResponse, GemError>>
+where
+    S: Stream<Item = Result<B, reqwest::Error>> + Unpin,
+    B: AsRef<[u8]>,
+{
+    let state = (
+        bytes,
+        SseParser::new(buffer_capacity),
+        VecDeque::new(),
+        false,
+    );
+    stream::unfold(
+        state,
+        |(mut bytes, mut parser, mut pending, mut done)| async move {
+            loop {
+                if let Some(chunk) = pending.pop_front() {
+                    return Some((chunk, (bytes, parser, pending, done)));
+                }
+                if done {
+                    return None;
+                }
+                match bytes.next().await {
+                    Some(Ok(chunk)) => pending.extend(parser.push(chunk.as_ref())),
+                    Some(Err(e)) => {
+                        done = true;
+                        pending.push_back(Err(GemError::StreamError(e.to_string())));
+                    }
+                    None => {
+                        done = true;
+                        pending.extend(parser.finish());
+                    }
+                }
+            }
+        },
+    )
+}
+
+/// The deadline and cancellation token of a call, started when the call is sent.
+pub(crate) struct CallGuard {
+    deadline: Option<(Duration, Pin<Box<Sleep>>)>,
+    cancelled: Option<Pin<Box<WaitForCancellationFutureOwned>>>,
+}
+
+impl CallGuard {
+    pub(crate) fn new(settings: &Settings) -> Self {
+        CallGuard {
+            deadline: settings
+                .get_deadline()
+                .map(|deadline| (deadline, Box::pin(tokio::time::sleep(deadline)))),
+            cancelled: settings
+                .get_cancellation_token()
+                .map(|token| Box::pin(token.clone().cancelled_owned())),
+        }
+    }
+
+    /// Returns the error once the call is cancelled or past its deadline.
+    fn poll_expired(&mut self, cx: &mut TaskContext<'_>) -> Poll<GemError> {
+        if let Some(cancelled) = &mut self.cancelled {
+            if cancelled.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(GemError::Cancelled);
+            }
+        }
+        if let Some((deadline, sleep)) = &mut self.deadline {
+            if sleep.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(GemError::DeadlineExceeded(*deadline));
+            }
+        }
+        Poll::Pending
+    }
+
+    /// Runs `future` until it completes, or drops it once the call expires.
+    pub(crate) async fn run<T>(
+        &mut self,
+        future: impl Future<Output = Result<T, GemError>>,
+    ) -> Result<T, GemError> {
+        let mut future = std::pin::pin!(future);
+        std::future::poll_fn(|cx| match self.poll_expired(cx) {
+            Poll::Ready(e) => Poll::Ready(Err(e)),
+            Poll::Pending => future.as_mut().poll(cx),
+        })
+        .await
+    }
+}
+
+/// The chunks of a streamed response, as returned by the client.
+pub(crate) type ChunkStream =
+    Pin<Box<dyn Stream<Item = Result<GenerateContentResponse, GemError>> + Send>>;
+
+/// What happens to the context when a stream fails before its first chunk, or fails or is
+/// dropped before its end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum IncompleteStreamPolicy {
+    /// Removes the user turn, leaving the context as it was before the call, so that the
+    /// message can be sent again.
+    #[default]
+    RemoveTurn,
+    /// Appends the parts received so far as the model turn. The user turn is removed if
+    /// nothing was received.
+    KeepPartial,
+    /// Keeps the user turn without a model turn.
+    KeepUserTurn,
+}
+
+impl IncompleteStreamPolicy {
+    /// Applies the policy to a call that received no part, `turn` being the context length
+    /// before the user turn.
+    pub(crate) fn discard(self, context: &mut Context, turn: usize) {
+        if self != IncompleteStreamPolicy::KeepUserTurn {
+            context.get_contents_mut().truncate(turn);
+        }
+    }
+}
+
+/// A stream of responses that appends the model's reply to the session's context.
+///
+/// The parts of the first candidate of every chunk are collected, with consecutive texts
+/// joined, and pushed as a single model turn once the stream ends. The session's
+/// `CandidateSelector` is not used, since chunks only hold partial candidates.
+pub struct ResponseStream<'a> {
+    inner: ChunkStream,
+    context: &'a mut Context,
+    turn: usize, // Length of the context before the user turn
+    parts: Vec<Part>,
+    policy: IncompleteStreamPolicy,
+    guard: CallGuard,
+    last: Option<GenerateContentResponse>, // Last chunk read by the forwarding adapters
+    finished: bool,
+}
+
+impl<'a> ResponseStream<'a> {
+    /// Wraps the chunks answering the last turn of `context`.
+    pub(crate) fn new(
+        inner: ChunkStream,
+        context: &'a mut Context,
+        policy: IncompleteStreamPolicy,
+        guard: CallGuard,
+    ) -> Self {
+        let turn = context.len().saturating_sub(1);
+        ResponseStream {
+            inner,
+            context,
+            turn,
+            parts: Vec::new(),
+            policy,
+            guard,
+            last: None,
+            finished: false,
+        }
+    }
+
+    /// Returns the text received so far.
+    pub fn get_text(&self) -> String {
+        self.parts
+            .iter()
+            .filter_map(|part| match part.get_data() {
+                PartData::Text { text } => Some(text.as_str()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Returns whether the stream has ended, successfully or not.
+    pub fn is_finished(&self) -> bool {
+        self.finished
+    }
+
+    /// Converts the stream into a stream of `StreamEvent`s, with errors as `GemError`s.
+    ///
+    /// The context is updated the same way as when the chunks are read directly.
+    pub fn events(self) -> EventStream<'a> {
+        EventStream {
+            inner: self,
+            pending: VecDeque::new(),
+            finish_reason: None,
+            usage_metadata: None,
+            done: false,
+        }
+    }
+
+    /// Writes the text deltas to `writer`, flushing after each one, and returns the full
+    /// response. The stream waits for every write, so a slow writer slows down reading.
+    ///
+    /// # Example
+    ///
+    /// ```no_run
+    /// use gem_rs::client::GemSession;
+    /// use gem_rs::types::Settings;
+    ///
+    /// # #[tokio::main]
+    /// # async fn main() -> Result<(), gem_rs::errors::GemError> {
+    /// let mut session = GemSession::new("API_KEY".to_string());
+    /// let response = session
+    ///     .send_message_stream("Tell me a story", &Settings::new())
+    ///     .await?
+    ///     .write_to(&mut tokio::io::stdout())
+    ///     .await?;
+    /// println!("\n{:?}", response.get_usage_metadata());
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub async fn write_to<W: AsyncWrite + Unpin>(
+        mut self,
+        writer: &mut W,
+    ) -> Result<GenerateContentResponse, GemError> {
+        while let Some(delta) = self.next_delta().await {
+            let delta = delta?;
+            let written = match writer.write_all(delta.as_bytes()).await {
+                Ok(()) => writer.flush().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = written {
+                return Err(GemError::StreamError(e.to_string()));
+            }
+        }
+        self.into_response()
+    }
+
+    /// Sends the text deltas to an mpsc channel and returns the full response. The stream
+    /// waits while the channel is full, and fails if its receiver is dropped.
+    pub async fn send_to(
+        mut self,
+        sender: &mpsc::Sender<String>,
+    ) -> Result<GenerateContentResponse, GemError> {
+        while let Some(delta) = self.next_delta().await {
+            if sender.send(delta?).await.is_err() {
+                return Err(GemError::StreamError(
+                    "The channel's receiver was dropped".to_string(),
+                ));
+            }
+        }
+        self.into_response()
+    }
+
+    /// Sends the text deltas to a broadcast channel and returns the full response.
+    ///
+    /// Broadcast channels never wait for their receivers: a receiver lagging more than the
+    /// channel's capacity misses deltas, and deltas sent without receivers are dropped.
+    /// Use `send_to` when every delta must be delivered.
+    pub async fn broadcast_to(
+        mut self,
+        sender: &broadcast::Sender<String>,
+    ) -> Result<GenerateContentResponse, GemError> {
+        while let Some(delta) = self.next_delta().await {
+            let _ = sender.send(delta?);
+        }
+        self.into_response()
+    }
+
+    /// Reads chunks until one holds text, and returns it.
+    async fn next_delta(&mut self) -> Option<Result<String, GemError>> {
+        loop {
+            let chunk = match std::future::poll_fn(|cx| self.poll_chunk(cx)).await? {
+                Ok(chunk) => chunk,
+                Err(e) => return Some(Err(e)),
+            };
+            let delta: String = chunk
+                .get_candidates()
+                .first()
+                .and_then(|candidate| candidate.get_content())
+                .map(|content| {
+                    content
+                        .get_parts()
+                        .iter()
+                        .filter_map(|part| match part.get_data() {
+                            PartData::Text { text } => Some(text.as_str()),
+                            _ => None,
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+            self.last = Some(chunk);
+            if !delta.is_empty() {
+                return Some(Ok(delta));
+            }
+        }
+    }
+
+    /// Returns the last chunk with the parts of the whole stream, once it has ended.
+    fn into_response(mut self) -> Result<GenerateContentResponse, GemError> {
+        let mut response = match self.last.take() {
+            Some(response) => response,
+            None => return Err(GemError::EmptyApiResponse),
+        };
+        if !self.parts.is_empty() {
+            response.set_streamed_content(Content::new(Some(Role::Model), self.parts.clone()));
+        }
+        Ok(response)
+    }
+
+    fn poll_chunk(
+        &mut self,
+        cx: &mut TaskContext<'_>,
+    ) -> Poll<Option<Result<GenerateContentResponse, GemError>>> {
+        if !self.finished {
+            if let Poll::Ready(e) = self.guard.poll_expired(cx) {
+                self.finish(false);
+                // Dropping the chunks closes the connection
+                self.inner = Box::pin(stream::empty());
+                return Poll::Ready(Some(Err(e)));
+            }
+        }
+        let item = match self.inner.as_mut().poll_next(cx) {
+            Poll::Ready(item) => item,
+            Poll::Pending => return Poll::Pending,
+        };
+        match &item {
+            Some(Ok(response)) if !self.finished => self.collect(response),
+            Some(Ok(_)) => {}
+            Some(Err(_)) => self.finish(false),
+            None => self.finish(true),
+        }
+        Poll::Ready(item)
+    }
+
+    fn collect(&mut self, response: &GenerateContentResponse) {
+        let content = match response
+            .get_candidates()
+            .first()
+            .and_then(|candidate| candidate.get_content())
+        {
+            Some(content) => content,
+            None => return,
+        };
+        for part in content.get_parts() {
+            if let (PartData::Text { text }, Some(last)) = (part.get_data(), self.parts.last_mut())
+            {
+                if let PartData::Text { text: previous } = last.get_data_mut() {
+                    previous.push_str(text);
+                    continue;
+                }
+            }
+            self.parts.push(part.clone());
+        }
+    }
+
+    /// Appends the model turn, or applies the policy if the stream failed, was dropped or
+    /// ended without any part.
+    fn finish(&mut self, complete: bool) {
+        if self.finished {
+            return;
+        }
+        self.finished = true;
+
+        let parts = self.parts.clone();
+        let keep_parts = complete || self.policy == IncompleteStreamPolicy::KeepPartial;
+        if keep_parts && !parts.is_empty() {
+            self.context
+                .push_content(Content::new(Some(Role::Model), parts));
+        } else {
+            self.policy.discard(self.context, self.turn);
+        }
+    }
+}
+
+impl Stream for ResponseStream<'_> {
+    type Item = Result<GenerateContentResponse, GemError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut().poll_chunk(cx)
+    }
+}
+
+impl Drop for ResponseStream<'_> {
+    fn drop(&mut self) {
+        self.finish(false);
+    }
+}
+
+/// An event of a streamed response.
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    /// Text generated since the previous event.
+    TextDelta(String),
+    /// A function call requested by the model.
+    FunctionCall(FunctionCall),
+    /// The prompt or the candidate was blocked. The block reason is only set for prompts.
+    SafetyBlock {
+        block_reason: Option<BlockReason>,
+        finish_reason: Option<FinishReason>,
+        safety_ratings: Vec<SafetyRating>,
+    },
+    /// The last event of a successful stream, with the usage of the whole response.
+    Finish {
+        finish_reason: Option<FinishReason>,
+        usage_metadata: Option<UsageMetadata>,
+    },
+}
+
+/// A stream of `StreamEvent`s, returned by `ResponseStream::events`.
+///
+/// Events are read from the first candidate of every chunk. A stream that fails ends
+/// with the error instead of `StreamEvent::Finish`.
+pub struct EventStream<'a> {
+    inner: ResponseStream<'a>,
+    pending: VecDeque<Result<StreamEvent, GemError>>,
+    finish_reason: Option<FinishReason>,
+    usage_metadata: Option<UsageMetadata>,
+    done: bool,
+}
+
+impl EventStream<'_> {
+    /// Returns the text received so far.
+    pub fn get_text(&self) -> String {
+        self.inner.get_text()
+    }
+
+    fn push_events(&mut self, response: GenerateContentResponse) {
+        let candidate = response.get_candidates().first();
+        if let Some(usage_metadata) = response.get_usage_metadata() {
+            self.usage_metadata = Some(usage_metadata.clone());
+        }
+        if let Some(finish_reason) = candidate.and_then(|c| c.get_finish_reason()) {
+            self.finish_reason = Some(finish_reason.clone());
+        }
+
+        if let Some(block_reason) = response.feedback() {
+            self.pending.push_back(Ok(StreamEvent::SafetyBlock {
+                block_reason: Some(block_reason),
+                finish_reason: self.finish_reason.clone(),
+                safety_ratings: response
+                    .get_prompt_feedback()
+                    .map(|feedback| feedback.get_safety_ratings().clone())
+                    .unwrap_or_default(),
+            }));
+        } else if let Some(candidate) = candidate.filter(|c| c.is_blocked()) {
+            self.pending.push_back(Ok(StreamEvent::SafetyBlock {
+                block_reason: None,
+                finish_reason: self.finish_reason.clone(),
+                safety_ratings: candidate.get_safety_ratings().cloned().unwrap_or_default(),
+            }));
+        }
+
+        let parts = candidate
+            .and_then(|c| c.get_content())
+            .map(|content| content.get_parts().as_slice())
+            .unwrap_or_default();
+        for part in parts {
+            match part.get_data() {
+                PartData::Text { text } if !text.is_empty() => {
+                    self.pending
+                        .push_back(Ok(StreamEvent::TextDelta(text.clone())));
+                }
+                PartData::FunctionCall { function_call } => {
+                    self.pending
+                        .push_back(Ok(StreamEvent::FunctionCall(function_call.clone())));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl Stream for EventStream<'_> {
+    type Item = Result<StreamEvent, GemError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        loop {
+            if let Some(event) = this.pending.pop_front() {
+                return Poll::Ready(Some(event));
+            }
+            if this.done {
+                return Poll::Ready(None);
+            }
+            match this.inner.poll_chunk(cx) {
+                Poll::Ready(Some(Ok(response))) => this.push_events(response),
+                Poll::Ready(Some(Err(e))) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Err(e)));
+                }
+                Poll::Ready(None) => {
+                    this.done = true;
+                    return Poll::Ready(Some(Ok(StreamEvent::Finish {
+                        finish_reason: this.finish_reason.take(),
+                        usage_metadata: this.usage_metadata.take(),
+                    })));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn chunks(texts: &[&str], error: bool) -> ChunkStream {
+        let mut items: Vec<Result<GenerateContentResponse, GemError>> = texts
+            .iter()
+            .map(|text| {
+                Ok(serde_json::from_value(json!({
+                    "candidates": [{ "content": { "parts": [{ "text": text }], "role": "model" } }]
+                }))
+                .unwrap())
+            })
+            .collect();
+        if error {
+            items.push(Err(GemError::StreamError("Invalid chunk".to_string())));
+        }
+        Box::pin(stream::iter(items))
+    }
+
+    fn user_turn() -> Context {
+        let mut context = Context::new();
+        context.push_message(None, "Hello".to_string());
+        context
+    }
+
+    #[tokio::test]
+    async fn test_response_stream_policies() {
+        // A complete stream appends the joined texts as the model turn
+        let mut context = user_turn();
+        let mut stream = ResponseStream::new(
+            chunks(&["Hi ", "there"], false),
+            &mut context,
+            IncompleteStreamPolicy::RemoveTurn,
+            CallGuard::new(&Settings::new()),
+        );
+        while stream.next().await.is_some() {}
+        assert_eq!(stream.get_text(), "Hi there");
+        assert!(stream.is_finished());
+        drop(stream);
+        assert_eq!(context.len(), 2);
+        assert_eq!(
+            context.get_contents()[1].get_text(),
+            Some("Hi there".to_string())
+        );
+
+        // A stream dropped early removes the user turn
+        let mut context = user_turn();
+        let mut stream = ResponseStream::new(
+            chunks(&["Hi ", "there"], false),
+            &mut context,
+            IncompleteStreamPolicy::RemoveTurn,
+            CallGuard::new(&Settings::new()),
+        );
+        stream.next().await.unwrap().unwrap();
+        assert_eq!(stream.get_text(), "Hi ");
+        drop(stream);
+        assert_eq!(context.len(), 0);
+
+        // A failed stream keeps what was received
+        let mut context = user_turn();
+        let mut stream = ResponseStream::new(
+            chunks(&["Hi "], true),
+            &mut context,
+            IncompleteStreamPolicy::KeepPartial,
+            CallGuard::new(&Settings::new()),
+        );
+        assert!(stream.next().await.unwrap().is_ok());
+        assert!(stream.next().await.unwrap().is_err());
+        drop(stream);
+        assert_eq!(
+            context.get_contents()[1].get_text(),
+            Some("Hi ".to_string())
+        );
+
+        let mut context = user_turn();
+        let stream = ResponseStream::new(
+            chunks(&["Hi "], false),
+            &mut context,
+            IncompleteStreamPolicy::KeepUserTurn,
+            CallGuard::new(&Settings::new()),
+        );
+        drop(stream);
+        assert_eq!(context.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_event_stream() {
+        let chunks: Vec<Result<GenerateContentResponse, GemError>> = vec![
+            json!({
+                "candidates": [{ "content": { "parts": [{ "text": "Hi" }], "role": "model" } }]
+            }),
+            json!({
+                "candidates": [{
+                    "content": {
+                        "parts": [{ "functionCall": { "name": "wave", "args": {} } }],
+                        "role": "model"
+                    },
+                    "finishReason": "STOP"
+                }],
+                "usageMetadata": { "promptTokenCount": 1, "totalTokenCount": 3 }
+            }),
+        ]
+        .into_iter()
+        .map(|chunk| Ok(serde_json::from_value(chunk).unwrap()))
+        .collect();
+
+        let mut context = user_turn();
+        let events: Vec<StreamEvent> = ResponseStream::new(
+            Box::pin(stream::iter(chunks)),
+            &mut context,
+            IncompleteStreamPolicy::RemoveTurn,
+            CallGuard::new(&Settings::new()),
+        )
+        .events()
+        .map(Result::unwrap)
+        .collect()
+        .await;
+
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], StreamEvent::TextDelta(text) if text == "Hi"));
+        assert!(matches!(&events[1], StreamEvent::FunctionCall(_)));
+        match &events[2] {
+            StreamEvent::Finish {
+                finish_reason,
+                usage_metadata,
+            } => {
+                assert_eq!(finish_reason, &Some(FinishReason::Stop));
+                assert_eq!(
+                    usage_metadata.as_ref().unwrap().get_total_token_count(),
+                    Some(3)
+                );
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        assert_eq!(context.get_contents()[1].get_function_calls().len(), 1);
+
+        // Blocked candidates are reported before the finish event
+        let blocked = serde_json::from_value(json!({
+            "candidates": [{
+                "finishReason": "SAFETY",
+                "safetyRatings": [{ "category": "HARM_CATEGORY_HARASSMENT", "blocked": true }]
+            }]
+        }))
+        .unwrap();
+        let mut context = user_turn();
+        let mut events = ResponseStream::new(
+            Box::pin(stream::iter(vec![Ok(blocked)])),
+            &mut context,
+            IncompleteStreamPolicy::RemoveTurn,
+            CallGuard::new(&Settings::new()),
+        )
+        .events();
+        match events.next().await.unwrap().unwrap() {
+            StreamEvent::SafetyBlock {
+                block_reason,
+                safety_ratings,
+                ..
+            } => {
+                assert!(block_reason.is_none());
+                assert!(safety_ratings[0].is_blocked());
+            }
+            event => panic!("Unexpected event: {:?}", event),
+        }
+        assert!(matches!(
+            events.next().await,
+            Some(Ok(StreamEvent::Finish { .. }))
+        ));
+        assert!(events.next().await.is_none());
+        drop(events);
+        assert_eq!(context.len(), 0);
+    }
+
+    #[test]
+    fn test_sse_parser() {
+        let mut parser = SseParser::new(16);
+        let event = json!({
+            "candidates": [{ "content": { "parts": [{ "text": "Hi" }], "role": "model" } }]
+        })
+        .to_string();
+
+        // Lines split across chunks, CRLF endings and comments
+        let (start, end) = event.split_at(10);
+        assert!(parser.push(b": keep-alive\r\n\r\nda").is_empty());
+        assert!(parser.push(format!("ta: {}", start).as_bytes()).is_empty());
+        let chunks = parser.push(format!("{}\r\n\r\n", end).as_bytes());
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].as_ref().unwrap().get_results(), vec!["Hi"]);
+
+        // Data split over several lines, and a last event without a blank line
+        assert!(parser.push(b"data: {\"candidates\":\ndata: []}\n\n").len() == 1);
+        assert!(parser.push(b"data: {\"candidates\": []}").is_empty());
+        assert_eq!(parser.finish().len(), 1);
+
+        assert!(matches!(
+            parser.push(b"data: {\n\n").pop(),
+            Some(Err(GemError::ParsingError(_)))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_forward_deltas() {
+        fn stream(context: &mut Context) -> ResponseStream<'_> {
+            ResponseStream::new(
+                chunks(&["Hi ", "there"], false),
+                context,
+                IncompleteStreamPolicy::RemoveTurn,
+                CallGuard::new(&Settings::new()),
+            )
+        }
+
+        let mut context = user_turn();
+        let mut output = Vec::new();
+        let response = stream(&mut context).write_to(&mut output).await.unwrap();
+        assert_eq!(output, b"Hi there");
+        assert_eq!(response.get_results(), vec!["Hi there"]);
+        assert_eq!(context.len(), 2);
+
+        // A channel of one delta holds the stream back until the first delta is read
+        let mut context = user_turn();
+        let (sender, mut receiver) = mpsc::channel(1);
+        let mut send = std::pin::pin!(stream(&mut context).send_to(&sender));
+        let pending = tokio::time::timeout(Duration::from_millis(50), &mut send).await;
+        assert!(pending.is_err());
+        assert_eq!(receiver.recv().await.unwrap(), "Hi ");
+        let response = send.await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), "there");
+        assert_eq!(response.get_results(), vec!["Hi there"]);
+
+        let mut context = user_turn();
+        let (sender, mut receiver) = broadcast::channel(4);
+        stream(&mut context).broadcast_to(&sender).await.unwrap();
+        assert_eq!(receiver.recv().await.unwrap(), "Hi ");
+        assert_eq!(receiver.recv().await.unwrap(), "there");
+
+        // The policy applies when the receiver is gone
+        let mut context = user_turn();
+        let (sender, receiver) = mpsc::channel(1);
+        drop(receiver);
+        let result = stream(&mut context).send_to(&sender).await;
+        assert!(matches!(result, Err(GemError::StreamError(_))));
+        assert_eq!(context.len(), 0);
+    }
+}
@@ -0,0 +1,1031 @@
+//! In-process mock of the Gemini API for integration tests (requires the `testing` feature).
+//!
+//! This module defines the `MockServer`, a small HTTP/1.1 server on a local port that
+//! mocks the `generateContent`, `streamGenerateContent`, `countTokens` and files endpoints.
+//! Sessions are pointed at it with `GemSessionBuilder::base_url`. Responses can be
+//! scripted per endpoint, including errors and streams split into arbitrary chunks, and
+//! the server falls back to echoing the last message and keeping uploaded files in memory.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+/// Endpoints of the Gemini API handled by the `MockServer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Endpoint {
+    GenerateContent,
+    StreamGenerateContent,
+    CountTokens,
+    /// Both the start (`POST`) and the upload (`PUT`) requests of a resumable upload
+    UploadFile,
+    GetFile,
+    ListFiles,
+    DeleteFile,
+}
+
+impl Endpoint {
+    fn from_request(method: &str, path: &str) -> Option<Self> {
+        if path.starts_with("/upload/") && path.ends_with("/files") {
+            return Some(Endpoint::UploadFile);
+        }
+        match method {
+            "POST" if path.ends_with(":generateContent") => Some(Endpoint::GenerateContent),
+            "POST" if path.ends_with(":streamGenerateContent") => {
+                Some(Endpoint::StreamGenerateContent)
+            }
+            "POST" if path.ends_with(":countTokens") => Some(Endpoint::CountTokens),
+            "GET" if path.ends_with("/files") => Some(Endpoint::ListFiles),
+            "GET" if path.contains("/files/") => Some(Endpoint::GetFile),
+            "DELETE" if path.contains("/files/") => Some(Endpoint::DeleteFile),
+            _ => None,
+        }
+    }
+}
+
+/// A request received by the `MockServer`.
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    method: String,
+    path: String,
+    query: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+impl MockRequest {
+    pub fn get_method(&self) -> &str {
+        &self.method
+    }
+
+    pub fn get_path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn get_query(&self) -> &str {
+        &self.query
+    }
+
+    /// Returns a header by its case-insensitive name.
+    pub fn get_header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .get(&name.to_ascii_lowercase())
+            .map(String::as_str)
+    }
+
+    pub fn get_body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn get_json(&self) -> Option<Value> {
+        serde_json::from_slice(&self.body).ok()
+    }
+
+    pub fn get_endpoint(&self) -> Option<Endpoint> {
+        Endpoint::from_request(&self.method, &self.path)
+    }
+}
+
+/// A response scripted on the `MockServer`.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    status: u16,
+    headers: Vec<(String, String)>,
+    chunks: Vec<String>,
+    objects: Vec<Value>, // Streamed objects, framed as a JSON array or as Server-Sent Events
+    stream: bool,
+    delay: Duration,
+    chunk_delay: Duration,
+}
+
+impl MockResponse {
+    /// A response with a JSON body.
+    pub fn json(status: u16, body: Value) -> Self {
+        MockResponse {
+            status,
+            headers: Vec::new(),
+            chunks: vec![body.to_string()],
+            objects: Vec::new(),
+            stream: false,
+            delay: Duration::ZERO,
+            chunk_delay: Duration::ZERO,
+        }
+    }
+
+    /// A `generateContent` response with a single candidate.
+    pub fn text(text: &str) -> Self {
+        Self::json(200, candidate_response(text, true))
+    }
+
+    /// A `streamGenerateContent` response sending each text in its own chunk.
+    pub fn stream(texts: &[&str]) -> Self {
+        let last = texts.len().saturating_sub(1);
+        Self::stream_objects(
+            texts
+                .iter()
+                .enumerate()
+                .map(|(i, text)| candidate_response(text, i == last))
+                .collect(),
+        )
+    }
+
+    /// A `streamGenerateContent` response sending each object in its own chunk, as a
+    /// JSON array or as Server-Sent Events when the request has `alt=sse`.
+    pub fn stream_objects(objects: Vec<Value>) -> Self {
+        MockResponse {
+            objects,
+            ..Self::stream_chunks(Vec::new())
+        }
+    }
+
+    /// A streamed response sending the raw chunks as they are, e.g. split in the middle
+    /// of a JSON object.
+    pub fn stream_chunks(chunks: Vec<String>) -> Self {
+        MockResponse {
+            status: 200,
+            headers: Vec::new(),
+            chunks,
+            objects: Vec::new(),
+            stream: true,
+            delay: Duration::ZERO,
+            chunk_delay: Duration::ZERO,
+        }
+    }
+
+    /// An error response in the API's `{"error": ...}` envelope.
+    pub fn error(status: u16, error_status: &str, message: &str) -> Self {
+        Self::json(
+            status,
+            json!({
+                "error": { "code": status, "message": message, "status": error_status }
+            }),
+        )
+    }
+
+    /// A 429 `RESOURCE_EXHAUSTED` error, asking to retry after `retry_after`.
+    pub fn rate_limited(retry_after: Duration) -> Self {
+        Self::error(429, "RESOURCE_EXHAUSTED", "Resource has been exhausted")
+            .with_header("Retry-After", &retry_after.as_secs_f64().to_string())
+    }
+
+    /// A 500 `INTERNAL` error.
+    pub fn server_error() -> Self {
+        Self::error(500, "INTERNAL", "An internal error has occurred")
+    }
+
+    /// A response whose prompt was blocked, with a candidate stopped for safety.
+    pub fn blocked_prompt(block_reason: &str) -> Self {
+        Self::json(
+            200,
+            json!({
+                "promptFeedback": { "blockReason": block_reason, "safetyRatings": [] },
+                "candidates": [{ "finishReason": "SAFETY", "index": 0 }]
+            }),
+        )
+    }
+
+    /// A response whose candidates were all blocked, without prompt feedback.
+    pub fn blocked_candidates() -> Self {
+        Self::json(
+            200,
+            json!({
+                "candidates": [{
+                    "finishReason": "SAFETY",
+                    "index": 0,
+                    "safetyRatings": [
+                        { "category": "HARM_CATEGORY_HARASSMENT", "probability": "HIGH", "blocked": true }
+                    ]
+                }]
+            }),
+        )
+    }
+
+    /// A response without candidates.
+    pub fn empty_candidates() -> Self {
+        Self::json(200, json!({ "candidates": [] }))
+    }
+
+    pub fn with_header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Returns the chunks to send, framing the streamed objects if there are any.
+    fn framed_chunks(&self, sse: bool) -> Vec<String> {
+        if self.objects.is_empty() {
+            return self.chunks.clone();
+        }
+        let last = self.objects.len() - 1;
+        self.objects
+            .iter()
+            .enumerate()
+            .map(|(i, object)| match sse {
+                true => format!("data: {}\r\n\r\n", object),
+                false => {
+                    let separator = if i == 0 { "[" } else { ",\r\n" };
+                    let end = if i == last { "]" } else { "" };
+                    format!("{}{}{}", separator, object, end)
+                }
+            })
+            .collect()
+    }
+
+    /// Waits before sending the response.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = delay;
+        self
+    }
+
+    /// Waits between the chunks of a streamed response.
+    pub fn with_chunk_delay(mut self, delay: Duration) -> Self {
+        self.chunk_delay = delay;
+        self
+    }
+}
+
+fn candidate_response(text: &str, last: bool) -> Value {
+    let mut candidate = json!({
+        "content": { "parts": [{ "text": text }], "role": "model" },
+        "index": 0
+    });
+    let mut response = json!({});
+    if last {
+        candidate["finishReason"] = json!("STOP");
+        response["usageMetadata"] = json!({
+            "promptTokenCount": 1,
+            "candidatesTokenCount": 1,
+            "totalTokenCount": 2
+        });
+    }
+    response["candidates"] = json!([candidate]);
+    response
+}
+
+#[derive(Debug, Default)]
+struct State {
+    base_url: String,
+    scripted: HashMap<Endpoint, VecDeque<MockResponse>>,
+    requests: Vec<MockRequest>,
+    files: BTreeMap<String, Value>,
+    uploads: HashMap<String, (String, String)>, // Upload id to display name and MIME type
+    next_id: usize,
+}
+
+/// A mock of the Gemini API listening on a local port, stopped when dropped.
+///
+/// # Example
+///
+/// ```
+/// use gem_rs::client::GemSession;
+/// use gem_rs::errors::GemError;
+/// use gem_rs::testing::{Endpoint, MockResponse, MockServer};
+/// use gem_rs::types::Settings;
+///
+/// # #[tokio::main]
+/// # async fn main() {
+/// let server = MockServer::start().await.unwrap();
+/// server.enqueue(Endpoint::GenerateContent, MockResponse::empty_candidates());
+///
+/// let mut session = GemSession::Builder()
+///     .base_url(&server.url())
+///     .build("key".to_string());
+/// let result = session.send_message("Hello", &Settings::new()).await;
+/// assert!(matches!(result, Err(GemError::EmptyApiResponse)));
+/// # }
+/// ```
+pub struct MockServer {
+    addr: SocketAddr,
+    state: Arc<Mutex<State>>,
+    handle: JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Starts the server on a free local port.
+    pub async fn start() -> std::io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let addr = listener.local_addr()?;
+        let state = Arc::new(Mutex::new(State {
+            base_url: format!("http://{}", addr),
+            ..State::default()
+        }));
+
+        let server_state = state.clone();
+        let handle = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let state = server_state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = handle_connection(stream, state).await {
+                        log::warn!("Mock server connection error: {}", e);
+                    }
+                });
+            }
+        });
+
+        Ok(MockServer {
+            addr,
+            state,
+            handle,
+        })
+    }
+
+    /// Returns the base URL to pass to `GemSessionBuilder::base_url`.
+    pub fn url(&self) -> String {
+        format!("http://{}", self.addr)
+    }
+
+    /// Queues a response for the next request to `endpoint`, instead of the default one.
+    pub fn enqueue(&self, endpoint: Endpoint, response: MockResponse) {
+        self.state
+            .lock()
+            .unwrap()
+            .scripted
+            .entry(endpoint)
+            .or_default()
+            .push_back(response);
+    }
+
+    /// Returns the requests received so far, in order.
+    pub fn get_requests(&self) -> Vec<MockRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Returns the names of the files currently stored, e.g. `files/1`.
+    pub fn get_file_names(&self) -> Vec<String> {
+        self.state.lock().unwrap().files.keys().cloned().collect()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.handle.abort();
+    }
+}
+
+async fn handle_connection(stream: TcpStream, state: Arc<Mutex<State>>) -> std::io::Result<()> {
+    let mut stream = BufReader::new(stream);
+    let request = match read_request(&mut stream).await? {
+        Some(request) => request,
+        None => return Ok(()),
+    };
+
+    let response = {
+        let mut state = state.lock().unwrap();
+        state.requests.push(request.clone());
+        let scripted = request
+            .get_endpoint()
+            .and_then(|endpoint| state.scripted.get_mut(&endpoint))
+            .and_then(|queue| queue.pop_front());
+        match scripted {
+            Some(response) => response,
+            None => default_response(&mut state, &request),
+        }
+    };
+
+    let sse = request.query.split('&').any(|pair| pair == "alt=sse");
+    write_response(stream.get_mut(), &response, sse).await
+}
+
+async fn read_request(stream: &mut BufReader<TcpStream>) -> std::io::Result<Option<MockRequest>> {
+    let mut line = String::new();
+    if stream.read_line(&mut line).await? == 0 {
+        return Ok(None);
+    }
+    let mut parts = line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let target = parts.next().unwrap_or_default();
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (path, query) = (path.to_string(), query.to_string());
+
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        if stream.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.insert(name.trim().to_ascii_lowercase(), value.trim().to_string());
+        }
+    }
+
+    let length = headers
+        .get("content-length")
+        .and_then(|length| length.parse::<usize>().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    stream.read_exact(&mut body).await?;
+
+    Ok(Some(MockRequest {
+        method,
+        path,
+        query,
+        headers,
+        body,
+    }))
+}
+
+async fn write_response(
+    stream: &mut TcpStream,
+    response: &MockResponse,
+    sse: bool,
+) -> std::io::Result<()> {
+    let reason = match response.status {
+        200 => "OK",
+        400 => "Bad Request",
+        404 => "Not Found",
+        429 => "Too Many Requests",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Unknown",
+    };
+    tokio::time::sleep(response.delay).await;
+    let content_type = match sse && response.stream {
+        true => "text/event-stream",
+        false => "application/json; charset=UTF-8",
+    };
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nConnection: close\r\nContent-Type: {}\r\n",
+        response.status, reason, content_type
+    );
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+
+    if !response.stream {
+        let body = response.chunks.concat();
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", body.len()));
+        stream.write_all(head.as_bytes()).await?;
+        stream.write_all(body.as_bytes()).await?;
+        return stream.flush().await;
+    }
+
+    head.push_str("Transfer-Encoding: chunked\r\n\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    for (i, chunk) in response.framed_chunks(sse).iter().enumerate() {
+        if i > 0 && !response.chunk_delay.is_zero() {
+            tokio::time::sleep(response.chunk_delay).await;
+        }
+        let frame = format!("{:x}\r\n{}\r\n", chunk.len(), chunk);
+        stream.write_all(frame.as_bytes()).await?;
+        stream.flush().await?;
+    }
+    stream.write_all(b"0\r\n\r\n").await?;
+    stream.flush().await
+}
+
+/// Returns the texts of a `generateContent` or `countTokens` request body.
+fn request_texts(body: &Value) -> Vec<String> {
+    let contents = body
+        .get("generateContentRequest")
+        .unwrap_or(body)
+        .get("contents")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+    contents
+        .iter()
+        .filter_map(|content| content.get("parts").and_then(Value::as_array))
+        .flatten()
+        .filter_map(|part| part.get("text").and_then(Value::as_str))
+        .map(String::from)
+        .collect()
+}
+
+fn not_found(name: &str) -> MockResponse {
+    MockResponse::error(404, "NOT_FOUND", &format!("{} not found", name))
+}
+
+/// Answers requests without a scripted response: echoes the last text, counts words as
+/// tokens and keeps uploaded files in memory.
+fn default_response(state: &mut State, request: &MockRequest) -> MockResponse {
+    let endpoint = match request.get_endpoint() {
+        Some(endpoint) => endpoint,
+        None => return not_found(&request.path),
+    };
+    let body = request.get_json().unwrap_or(Value::Null);
+    let echo = format!(
+        "Echo: {}",
+        request_texts(&body).last().cloned().unwrap_or_default()
+    );
+    // Resource names come after the version segment, e.g. `/v1beta/files/1`
+    let name = request
+        .path
+        .splitn(3, '/')
+        .nth(2)
+        .unwrap_or_default()
+        .to_string();
+
+    match endpoint {
+        Endpoint::GenerateContent => MockResponse::text(&echo),
+        Endpoint::StreamGenerateContent => {
+            let words: Vec<String> = echo.split_inclusive(' ').map(String::from).collect();
+            MockResponse::stream(&words.iter().map(String::as_str).collect::<Vec<_>>())
+        }
+        Endpoint::CountTokens => {
+            let tokens: usize = request_texts(&body)
+                .iter()
+                .map(|text| text.split_whitespace().count())
+                .sum();
+            MockResponse::json(200, json!({ "totalTokens": tokens }))
+        }
+        Endpoint::UploadFile if request.method == "POST" => {
+            state.next_id += 1;
+            let upload_id = state.next_id.to_string();
+            let display_name = body["file"]["display_name"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string();
+            let mime_type = request
+                .get_header("X-Goog-Upload-Header-Content-Type")
+                .unwrap_or("application/octet-stream")
+                .to_string();
+            state
+                .uploads
+                .insert(upload_id.clone(), (display_name, mime_type));
+            MockResponse::json(200, json!({})).with_header(
+                "X-Goog-Upload-URL",
+                &format!(
+                    "{}{}?upload_id={}&upload_protocol=resumable",
+                    state.base_url, request.path, upload_id
+                ),
+            )
+        }
+        Endpoint::UploadFile => {
+            let upload_id = request
+                .query
+                .split('&')
+                .find_map(|pair| pair.strip_prefix("upload_id="))
+                .unwrap_or_default()
+                .to_string();
+            let (display_name, mime_type) = match state.uploads.remove(&upload_id) {
+                Some(upload) => upload,
+                None => return not_found("Upload"),
+            };
+
+            let version = request.path.split('/').nth(2).unwrap_or("v1beta");
+            let now = chrono::Utc::now();
+            let name = format!("files/{}", upload_id);
+            let file = json!({
+                "name": name,
+                "displayName": display_name,
+                "mimeType": mime_type,
+                "sizeBytes": request.body.len().to_string(),
+                "createTime": now.to_rfc3339(),
+                "updateTime": now.to_rfc3339(),
+                "expirationTime": (now + chrono::Duration::hours(48)).to_rfc3339(),
+                "sha256Hash": sha256::digest(request.body.as_slice()),
+                "uri": format!("{}/{}/{}", state.base_url, version, name),
+                "state": "ACTIVE"
+            });
+            state.files.insert(name, file.clone());
+            MockResponse::json(200, json!({ "file": file }))
+        }
+        Endpoint::GetFile => match state.files.get(&name) {
+            Some(file) => MockResponse::json(200, file.clone()),
+            None => not_found(&name),
+        },
+        Endpoint::ListFiles => MockResponse::json(
+            200,
+            json!({ "files": state.files.values().cloned().collect::<Vec<_>>() }),
+        ),
+        Endpoint::DeleteFile => match state.files.remove(&name) {
+            Some(_) => MockResponse::json(200, json!({})),
+            None => not_found(&name),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::api::Backend;
+    use crate::client::GemSession;
+    use crate::errors::GemError;
+    use crate::middleware::{GenerateRequest, Middleware};
+    use crate::retry::RetryPolicy;
+    use crate::stream::{IncompleteStreamPolicy, StreamOptions};
+    use crate::tools::Tool;
+    use crate::types::FunctionDeclaration;
+    use crate::types::{CancellationToken, FileManager, Settings};
+
+    #[tokio::test]
+    async fn test_send_context_errors() {
+        let server = MockServer::start().await.unwrap();
+        let mut session = GemSession::Builder()
+            .base_url(&server.url())
+            .retry_policy(
+                RetryPolicy::new()
+                    .max_attempts(2)
+                    .base_delay(Duration::from_millis(10)),
+            )
+            .build("key".to_string());
+        let settings = Settings::new();
+
+        server.enqueue(Endpoint::GenerateContent, MockResponse::empty_candidates());
+        let result = session.send_message("Hello", &settings).await;
+        assert!(matches!(result, Err(GemError::EmptyApiResponse)));
+
+        server.enqueue(
+            Endpoint::GenerateContent,
+            MockResponse::blocked_candidates(),
+        );
+        let result = session.send_message("Hello", &settings).await;
+        assert!(matches!(result, Err(GemError::AllCandidatesBlocked)));
+
+        server.enqueue(
+            Endpoint::GenerateContent,
+            MockResponse::blocked_prompt("SAFETY"),
+        );
+        let result = session.send_message("Hello", &settings).await;
+        assert!(matches!(result, Err(GemError::FeedbackError(_))));
+
+        // Retried once, then the error is returned
+        server.enqueue(Endpoint::GenerateContent, MockResponse::server_error());
+        server.enqueue(Endpoint::GenerateContent, MockResponse::server_error());
+        match session.send_message("Hello", &settings).await {
+            Err(GemError::GeminiAPIError(error)) => assert_eq!(error.get_code(), 500),
+            other => panic!("Unexpected result: {:?}", other),
+        }
+
+        server.enqueue(
+            Endpoint::GenerateContent,
+            MockResponse::rate_limited(Duration::ZERO),
+        );
+        let response = session.send_message("Hello", &settings).await.unwrap();
+        assert_eq!(response.get_results(), vec!["Echo: Hello"]);
+
+        let requests = server.get_requests();
+        assert_eq!(requests.len(), 7);
+        assert_eq!(requests[0].get_header("x-goog-api-key"), Some("key"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_tokens_and_files() {
+        let server = MockServer::start().await.unwrap();
+        let mut session = GemSession::Builder()
+            .base_url(&server.url())
+            .build("key".to_string());
+        let settings = Settings::new();
+
+        // The second chunk ends in the middle of the second object
+        let chunk = candidate_response("lo", true).to_string();
+        let (start, end) = chunk.split_at(10);
+        server.enqueue(
+            Endpoint::StreamGenerateContent,
+            MockResponse::stream_chunks(vec![
+                format!("[{}", candidate_response("Hel", false)),
+                format!(",\r\n{}", start),
+                format!("{}]", end),
+            ])
+            .with_chunk_delay(Duration::from_millis(10)),
+        );
+        let mut stream = session
+            .send_message_stream("Hello", &settings)
+            .await
+            .unwrap();
+        let mut texts = Vec::new();
+        while let Some(chunk) = stream.next().await {
+            texts.push(chunk.unwrap().get_results().concat());
+        }
+        assert_eq!(texts, vec!["Hel", "lo"]);
+        drop(stream);
+
+        // The streamed reply is part of the counted context
+        let tokens = session.count_tokens(&settings).await.unwrap();
+        assert_eq!(tokens.get_total_tokens(), 2);
+
+        let mut files = FileManager::with_client(session.client());
+        files
+            .add_file_from_bytes("notes.txt", b"Hello there".to_vec(), "text/plain")
+            .await
+            .unwrap();
+        assert_eq!(server.get_file_names(), vec!["files/1"]);
+
+        files.fetch_list().await.unwrap();
+        files.clear_files().await;
+        assert!(server.get_file_names().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_rejected_request_errors() {
+        struct Reject(Arc<Mutex<Vec<String>>>);
+
+        #[async_trait::async_trait]
+        impl Middleware for Reject {
+            async fn before_request(&self, _request: &mut GenerateRequest) -> Result<(), GemError> {
+                Err(GemError::StreamError("rejected".to_string()))
+            }
+
+            async fn on_error(&self, _request: &GenerateRequest, error: &GemError) {
+                self.0.lock().unwrap().push(error.to_string());
+            }
+        }
+
+        let server = MockServer::start().await.unwrap();
+        let errors = Arc::new(Mutex::new(Vec::new()));
+        let mut session = GemSession::Builder()
+            .base_url(&server.url())
+            .middleware(Reject(errors.clone()))
+            .build("key".to_string());
+        let settings = Settings::new();
+
+        assert!(session.send_message("Hello", &settings).await.is_err());
+        assert!(session
+            .send_message_stream("Hello", &settings)
+            .await
+            .is_err());
+        assert_eq!(errors.lock().unwrap().len(), 2);
+        assert!(server.get_requests().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_stream_error_before_first_chunk() {
+        let server = MockServer::start().await.unwrap();
+        let mut session = GemSession::Builder()
+            .base_url(&server.url())
+            .build("key".to_string());
+        let settings = Settings::new();
+
+        server.enqueue(
+            Endpoint::StreamGenerateContent,
+            MockResponse::server_error(),
+        );
+        assert!(matches!(
+            session.send_message_stream("Hello", &settings).await,
+            Err(GemError::GeminiAPIError(_))
+        ));
+        assert!(session.context().get_contents().is_empty());
+
+        let mut session = GemSession::Builder()
+            .base_url(&server.url())
+            .incomplete_stream_policy(IncompleteStreamPolicy::KeepUserTurn)
+            .build("key".to_string());
+        server.enqueue(
+            Endpoint::StreamGenerateContent,
+            MockResponse::server_error(),
+        );
+        assert!(session
+            .send_message_stream("Hello", &settings)
+            .await
+            .is_err());
+        assert_eq!(session.context().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_vertex_ai_token() {
+        let server = MockServer::start().await.unwrap();
+        let mut session = GemSession::Builder()
+            .backend(Backend::vertex_ai("my-project", "us-central1"))
+            .base_url(&server.url())
+            .build("ya29.token".to_string());
+
+        session
+            .send_message("Hello", &Settings::new())
+            .await
+            .unwrap();
+
+        let requests = server.get_requests();
+        assert!(requests[0]
+            .get_path()
+            .contains("/projects/my-project/locations/us-central1/"));
+        assert_eq!(
+            requests[0].get_header("authorization"),
+            Some("Bearer ya29.token")
+        );
+        assert_eq!(requests[0].get_header("x-goog-api-key"), None);
+    }
+
+    #[tokio::test]
+    async fn test_sse_stream() {
+        let server = MockServer::start().await.unwrap();
+        let settings = Settings::new();
+        let large_call = json!({
+            "candidates": [{
+                "content": {
+                    "parts": [{
+                        "functionCall": { "name": "save", "args": { "text": "a".repeat(4096) } }
+                    }],
+                    "role": "model"
+                },
+                "finishReason": "STOP"
+            }]
+        });
+
+        // The chunk is larger than the JSON array's default maximum object size
+        server.enqueue(
+            Endpoint::StreamGenerateContent,
+            MockResponse::stream_objects(vec![large_call.clone()]),
+        );
+        let mut session = GemSession::Builder()
+            .base_url(&server.url())
+            .build("key".to_string());
+        let mut stream = session
+            .send_message_stream("Save it", &settings)
+            .await
+            .unwrap();
+        assert!(stream.next().await.unwrap().is_err());
+        drop(stream);
+
+        server.enqueue(
+            Endpoint::StreamGenerateContent,
+            MockResponse::stream_objects(vec![candidate_response("Saving", false), large_call]),
+        );
+        let mut session = GemSession::Builder()
+            .base_url(&server.url())
+            .stream_options(StreamOptions::sse().buffer_capacity(256))
+            .build("key".to_string());
+        let chunks: Vec<_> = session
+            .send_message_stream("Save it", &settings)
+            .await
+            .unwrap()
+            .collect()
+            .await;
+        assert_eq!(chunks.len(), 2);
+        let calls = chunks[1].as_ref().unwrap().get_function_calls();
+        assert_eq!(calls[0].get_name(), "save");
+
+        let requests = server.get_requests();
+        assert_eq!(requests[1].get_query(), "alt=sse");
+    }
+
+    #[tokio::test]
+    async fn test_deadline_and_cancellation() {
+        let server = MockServer::start().await.unwrap();
+        let mut session = GemSession::Builder()
+            .base_url(&server.url())
+            .build("key".to_string());
+
+        // The user turn of an expired call is removed
+        server.enqueue(
+            Endpoint::GenerateContent,
+            MockResponse::text("Too late").with_delay(Duration::from_millis(500)),
+        );
+        let settings = Settings::builder()
+            .deadline(Duration::from_millis(50))
+            .build();
+        let result = session.send_message("Hello", &settings).await;
+        assert!(matches!(result, Err(GemError::DeadlineExceeded(_))));
+        assert!(session.context().get_contents().is_empty());
+
+        // Cancelling stops the stream between chunks
+        server.enqueue(
+            Endpoint::StreamGenerateContent,
+            MockResponse::stream(&["One ", "two ", "three"])
+                .with_chunk_delay(Duration::from_millis(200)),
+        );
+        let token = CancellationToken::new();
+        let settings = Settings::builder()
+            .cancellation_token(token.clone())
+            .build();
+        let mut stream = session
+            .send_message_stream("Count", &settings)
+            .await
+            .unwrap();
+        assert!(stream.next().await.unwrap().is_ok());
+        token.cancel();
+        assert!(stream.next().await.unwrap().is_err());
+        assert!(stream.next().await.is_none());
+        drop(stream);
+        assert!(session.context().get_contents().is_empty());
+
+        // A token cancelled beforehand fails the call before it is sent
+        let result = session.send_message("Hello", &settings).await;
+        assert!(matches!(result, Err(GemError::Cancelled)));
+        assert!(session.context().get_contents().is_empty());
+        assert_eq!(server.get_requests().len(), 2);
+    }
+
+    struct Failing;
+
+    #[async_trait::async_trait]
+    impl Tool for Failing {
+        fn declaration(&self) -> FunctionDeclaration {
+            FunctionDeclaration::new("save", "Saves a note", None)
+        }
+
+        async fn call(&self, _args: Value) -> Result<Value, GemError> {
+            Err(GemError::ToolError("Disk full".to_string()))
+        }
+    }
+
+    /// A response asking to call the `save` function.
+    fn function_call() -> MockResponse {
+        MockResponse::json(
+            200,
+            json!({
+                "candidates": [{
+                    "content": {
+                        "parts": [{ "functionCall": { "name": "save", "args": {} } }],
+                        "role": "model"
+                    },
+                    "finishReason": "STOP"
+                }]
+            }),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_run_with_failing_tool() {
+        let server = MockServer::start().await.unwrap();
+        let mut session = GemSession::Builder()
+            .base_url(&server.url())
+            .build("key".to_string());
+        let settings = Settings::new();
+        let tools: Vec<Box<dyn Tool>> = vec![Box::new(Failing)];
+
+        server.enqueue(Endpoint::GenerateContent, function_call());
+        server.enqueue(
+            Endpoint::GenerateContent,
+            MockResponse::text("I could not save it"),
+        );
+        let response = session
+            .run_with_tools("Save this", &tools, &settings, 3)
+            .await
+            .unwrap();
+        assert_eq!(response.get_results(), vec!["I could not save it"]);
+
+        // The failure was sent back as the function's response
+        let body = server.get_requests()[1].get_json().unwrap();
+        assert_eq!(
+            body["contents"][2]["parts"][0]["functionResponse"]["response"]["error"],
+            "Tool error: Disk full"
+        );
+        assert_eq!(session.context().len(), 4);
+
+        // The session can still be used
+        let response = session.send_message("Thanks", &settings).await.unwrap();
+        assert_eq!(response.get_results(), vec!["Echo: Thanks"]);
+
+        // Reaching the limit removes the unanswered function call along with the whole loop
+        server.enqueue(Endpoint::GenerateContent, function_call());
+        server.enqueue(Endpoint::GenerateContent, function_call());
+        let result = session
+            .run_with_tools("Save this", &tools, &settings, 1)
+            .await;
+        assert!(matches!(result, Err(GemError::ToolLoopLimitReached(1))));
+        assert_eq!(session.context().len(), 6);
+
+        session.send_message("Never mind", &settings).await.unwrap();
+        let body = server.get_requests().last().unwrap().get_json().unwrap();
+        assert_eq!(body["contents"].as_array().unwrap().len(), 7);
+        assert_eq!(body["contents"][6]["parts"][0]["text"], "Never mind");
+        assert_eq!(session.context().len(), 8);
+
+        // The deadline covers the whole loop, and every turn of an expired loop is removed.
+        // Each round fits the deadline with half a second to spare, but both rounds exceed
+        // it by as much, so that scheduling delays do not change the outcome
+        server.enqueue(
+            Endpoint::GenerateContent,
+            function_call().with_delay(Duration::from_secs(1)),
+        );
+        server.enqueue(
+            Endpoint::GenerateContent,
+            MockResponse::text("Saved").with_delay(Duration::from_secs(1)),
+        );
+        let settings = Settings::builder()
+            .deadline(Duration::from_millis(1500))
+            .build();
+        let result = session
+            .run_with_tools("Save this", &tools, &settings, 3)
+            .await;
+        assert!(matches!(result, Err(GemError::DeadlineExceeded(_))));
+        assert_eq!(session.context().len(), 8);
+    }
+
+    #[tokio::test]
+    async fn test_tool_round_over_token_limit() {
+        let server = MockServer::start().await.unwrap();
+        let mut session = GemSession::Builder()
+            .base_url(&server.url())
+            .check_token_limit(true)
+            .build("key".to_string());
+        let settings = Settings::new();
+        let tools: Vec<Box<dyn Tool>> = vec![Box::new(Failing)];
+
+        server.enqueue(
+            Endpoint::CountTokens,
+            MockResponse::json(200, json!({ "totalTokens": 5 })),
+        );
+        server.enqueue(Endpoint::GenerateContent, function_call());
+        server.enqueue(
+            Endpoint::CountTokens,
+            MockResponse::json(200, json!({ "totalTokens": 3_000_000 })),
+        );
+        let result = session
+            .run_with_tools("Save this", &tools, &settings, 3)
+            .await;
+        assert!(matches!(result, Err(GemError::TokenLimitExceeded(_))));
+        assert!(session.context().get_contents().is_empty());
+
+        // No function call is left without its response
+        let response = session.send_message("Hello", &settings).await.unwrap();
+        assert_eq!(response.get_results(), vec!["Echo: Hello"]);
+        assert_eq!(session.context().len(), 2);
+    }
+}
@@ -0,0 +1,138 @@
+//! Tool execution support for the Gem-rs library.
+//!
+//! This module defines the `Tool` trait, which lets Rust functions be registered with a
+//! `GemSession` and executed automatically whenever the model asks for them through
+//! function calling (see `GemSession::run_with_tools`).
+
+use async_trait::async_trait;
+use serde_json::{json, Value};
+
+use crate::errors::GemError;
+use crate::types::{FunctionCall, FunctionDeclaration, FunctionResponse};
+
+/// A function that the model can call during `GemSession::run_with_tools`.
+///
+/// # Example
+///
+/// ```
+/// use async_trait::async_trait;
+/// use gem_rs::errors::GemError;
+/// use gem_rs::tools::Tool;
+/// use gem_rs::types::FunctionDeclaration;
+/// use serde_json::{json, Value};
+///
+/// struct Weather;
+///
+/// #[async_trait]
+/// impl Tool for Weather {
+///     fn declaration(&self) -> FunctionDeclaration {
+///         FunctionDeclaration::new(
+///             "get_weather",
+///             "Returns the current temperature of a city",
+///             Some(json!({
+///                 "type": "OBJECT",
+///                 "properties": { "city": { "type": "STRING" } },
+///                 "required": ["city"]
+///             })),
+///         )
+///     }
+///
+///     async fn call(&self, _args: Value) -> Result<Value, GemError> {
+///         Ok(json!({ "temperature": 41 }))
+///     }
+/// }
+/// ```
+#[async_trait]
+pub trait Tool: Send + Sync {
+    /// Returns the declaration sent to the model, its name is used to route function calls.
+    fn declaration(&self) -> FunctionDeclaration;
+
+    /// Executes the tool with the arguments generated by the model.
+    ///
+    /// Values that are not JSON objects are wrapped as `{"result": value}` before being
+    /// sent back, since the API only accepts objects as function responses. Errors are
+    /// sent back as `{"error": message}`, so that the model can recover from them.
+    async fn call(&self, args: Value) -> Result<Value, GemError>;
+}
+
+/// Runs every function call against the matching tool and collects the responses.
+///
+/// Every call gets a response, so that the model's turn is always answered: failed tools
+/// and functions without a tool are answered with `{"error": message}`.
+pub(crate) async fn execute_calls(
+    tools: &[Box<dyn Tool>],
+    calls: Vec<FunctionCall>,
+) -> Vec<FunctionResponse> {
+    let mut responses = Vec::new();
+    for call in calls {
+        let result = match tools
+            .iter()
+            .find(|tool| tool.declaration().get_name() == call.get_name())
+        {
+            Some(tool) => {
+                let args = call.get_args().cloned().unwrap_or(json!({}));
+                tool.call(args).await
+            }
+            None => Err(GemError::ToolError(format!(
+                "No tool registered for function: {}",
+                call.get_name()
+            ))),
+        };
+
+        let response = match result {
+            Ok(value) => into_object(value),
+            Err(e) => {
+                log::warn!("Tool {} failed: {}", call.get_name(), e);
+                json!({ "error": e.to_string() })
+            }
+        };
+        responses.push(FunctionResponse::new(call.get_name(), response));
+    }
+    responses
+}
+
+fn into_object(value: Value) -> Value {
+    match value {
+        Value::Object(_) => value,
+        value => json!({ "result": value }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Echo;
+
+    #[async_trait]
+    impl Tool for Echo {
+        fn declaration(&self) -> FunctionDeclaration {
+            FunctionDeclaration::new("echo", "Returns its arguments", None)
+        }
+
+        async fn call(&self, args: Value) -> Result<Value, GemError> {
+            Ok(args["text"].clone())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_execute_calls() {
+        let tools: Vec<Box<dyn Tool>> = vec![Box::new(Echo)];
+        let calls: Vec<FunctionCall> = serde_json::from_value(json!([
+            { "name": "echo", "args": { "text": "hi" } }
+        ]))
+        .unwrap();
+
+        let responses = execute_calls(&tools, calls).await;
+        assert_eq!(responses[0].get_name(), "echo");
+        assert_eq!(responses[0].get_response(), &json!({ "result": "hi" }));
+
+        let calls: Vec<FunctionCall> =
+            serde_json::from_value(json!([{ "name": "missing" }])).unwrap();
+        let responses = execute_calls(&tools, calls).await;
+        assert_eq!(
+            responses[0].get_response()["error"],
+            "Tool error: No tool registered for function: missing"
+        );
+    }
+}
@@ -2,11 +2,17 @@ use std::{collections::HashMap, path::Path};
 
 use base64::{engine::general_purpose, Engine as _};
 use log::log;
-use reqwest::header;
-use serde::{Deserialize, Serialize};
+use reqwest::{header, Method};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::{json, Value};
 use tokio::sync::Mutex;
+pub use tokio_util::sync::CancellationToken;
 
+#[cfg(feature = "schema")]
+use crate::schema::GeminiSchema;
+use crate::api::{Backend, Models};
+use crate::client::Client;
+use crate::retry::parse_duration;
 use crate::{errors::GemError, utils::get_mime_type};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -15,6 +21,14 @@ pub enum PartData {
     InlineData { inline_data: Blob },
     FileData { file_data: FileData },
     Text { text: String },
+    FunctionCall {
+        #[serde(rename = "functionCall", alias = "function_call")]
+        function_call: FunctionCall,
+    },
+    FunctionResponse {
+        #[serde(rename = "functionResponse", alias = "function_response")]
+        function_response: FunctionResponse,
+    },
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Default)]
@@ -27,7 +41,7 @@ pub(crate) enum Role {
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")] // Ensure enum variants match the JSON casing
-pub(crate) enum FinishReason {
+pub enum FinishReason {
     FinishReasonUnspecified, // Default value. This value is unused.
     Stop,                    // Natural stop point of the model or provided stop sequence.
     MaxTokens,  // The maximum number of tokens as specified in the request was reached.
@@ -39,14 +53,19 @@ pub(crate) enum FinishReason {
     ProhibitedContent, // Token generation stopped for potentially containing prohibited content.
     Spii, // Token generation stopped because the content potentially contains Sensitive Personally Identifiable Information (SPII).
     MalformedFunctionCall, // The function call generated by the model is invalid.
+    #[serde(other)]
+    Unknown, // A reason added after this version, Vertex AI has a few more.
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct GenerateContentResponse {
-    candidates: Vec<Candidate>,
+    #[serde(default)]
+    candidates: Vec<Candidate>, // Omitted by Vertex AI when the prompt is blocked
     prompt_feedback: Option<PromptFeedback>, // This is optional
     usage_metadata: Option<UsageMetadata>,   // This is optional
+    #[serde(skip)]
+    selected: Option<usize>, // Index of the candidate kept in the session's context
 }
 
 impl GenerateContentResponse {
@@ -66,10 +85,52 @@ impl GenerateContentResponse {
         texts
     }
 
+    /// Returns the candidate picked by the session's `CandidateSelector`, or the first one.
+    ///
+    /// The other candidates are still available through `get_candidates`.
+    pub fn get_selected_candidate(&self) -> Option<&Candidate> {
+        self.candidates.get(self.selected.unwrap_or(0))
+    }
+
+    pub fn get_selected_result(&self) -> Option<String> {
+        self.get_selected_candidate().and_then(|c| c.get_text())
+    }
+
+    pub(crate) fn set_selected(&mut self, index: usize) {
+        self.selected = Some(index);
+    }
+
+    pub fn get_function_calls(&self) -> Vec<FunctionCall> {
+        match self.get_selected_candidate().and_then(|c| c.get_content()) {
+            Some(content) => content.get_function_calls(),
+            None => Vec::new(),
+        }
+    }
+
     pub fn get_usage_metadata(&self) -> Option<&UsageMetadata> {
         self.usage_metadata.as_ref()
     }
 
+    /// Replaces the content of the first candidate with the parts joined from a stream.
+    pub(crate) fn set_streamed_content(&mut self, content: Content) {
+        match self.candidates.first_mut() {
+            Some(candidate) => candidate.content = Some(content),
+            None => self.candidates.push(Candidate {
+                content: Some(content),
+                finish_reason: None,
+                safety_ratings: None,
+                token_count: None,
+                index: Some(0),
+                avg_logprobs: None,
+                logprobs_result: None,
+            }),
+        }
+    }
+
+    pub(crate) fn get_prompt_feedback(&self) -> Option<&PromptFeedback> {
+        self.prompt_feedback.as_ref()
+    }
+
     pub(crate) fn feedback(&self) -> Option<BlockReason> {
         match self.prompt_feedback.is_some()
             && self
@@ -93,6 +154,8 @@ pub struct Candidate {
     safety_ratings: Option<Vec<SafetyRating>>, // List of safety ratings for the response
     token_count: Option<i32>,            // The token count for this candidate
     index: Option<i32>,                  // Index of the candidate in the list
+    avg_logprobs: Option<f64>,           // Average log probability of the candidate's tokens
+    logprobs_result: Option<LogprobsResult>, // Per-token logprobs, if response_logprobs is set
 }
 
 impl Candidate {
@@ -100,6 +163,22 @@ impl Candidate {
         self.content.as_ref()
     }
 
+    pub fn get_text(&self) -> Option<String> {
+        self.content.as_ref().and_then(|content| content.get_text())
+    }
+
+    pub fn get_index(&self) -> Option<i32> {
+        self.index
+    }
+
+    pub fn get_finish_reason(&self) -> Option<&FinishReason> {
+        self.finish_reason.as_ref()
+    }
+
+    pub fn get_safety_ratings(&self) -> Option<&Vec<SafetyRating>> {
+        self.safety_ratings.as_ref()
+    }
+
     pub(crate) fn is_blocked(&self) -> bool {
         (self.finish_reason == Some(FinishReason::Safety))
             || (self.finish_reason == Some(FinishReason::Recitation))
@@ -109,6 +188,71 @@ impl Candidate {
     pub(crate) fn get_token_count(&self) -> Option<i32> {
         self.token_count
     }
+
+    pub fn get_avg_logprobs(&self) -> Option<f64> {
+        self.avg_logprobs
+    }
+
+    pub fn get_logprobs_result(&self) -> Option<&LogprobsResult> {
+        self.logprobs_result.as_ref()
+    }
+
+    pub(crate) fn is_malformed_function_call(&self) -> bool {
+        self.finish_reason == Some(FinishReason::MalformedFunctionCall)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogprobsCandidate {
+    token: Option<String>,
+    token_id: Option<i32>,
+    log_probability: Option<f64>,
+}
+
+impl LogprobsCandidate {
+    pub fn get_token(&self) -> Option<&str> {
+        self.token.as_deref()
+    }
+
+    pub fn get_token_id(&self) -> Option<i32> {
+        self.token_id
+    }
+
+    pub fn get_log_probability(&self) -> Option<f64> {
+        self.log_probability
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TopCandidates {
+    #[serde(default)]
+    candidates: Vec<LogprobsCandidate>, // Sorted by log probability, descending
+}
+
+impl TopCandidates {
+    pub fn get_candidates(&self) -> &Vec<LogprobsCandidate> {
+        &self.candidates
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LogprobsResult {
+    #[serde(default)]
+    top_candidates: Vec<TopCandidates>, // One entry per decoding step
+    #[serde(default)]
+    chosen_candidates: Vec<LogprobsCandidate>, // The chosen token of each decoding step
+}
+
+impl LogprobsResult {
+    pub fn get_top_candidates(&self) -> &Vec<TopCandidates> {
+        &self.top_candidates
+    }
+
+    pub fn get_chosen_candidates(&self) -> &Vec<LogprobsCandidate> {
+        &self.chosen_candidates
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -118,6 +262,18 @@ pub struct Content {
 }
 
 impl Content {
+    pub(crate) fn new(role: Option<Role>, parts: Vec<Part>) -> Self {
+        Content { parts, role }
+    }
+
+    pub fn get_parts(&self) -> &Vec<Part> {
+        &self.parts
+    }
+
+    pub fn get_parts_mut(&mut self) -> &mut Vec<Part> {
+        &mut self.parts
+    }
+
     pub fn get_text(&self) -> Option<String> {
         for part in &self.parts {
             match &part.data {
@@ -127,6 +283,16 @@ impl Content {
         }
         None
     }
+
+    pub fn get_function_calls(&self) -> Vec<FunctionCall> {
+        let mut calls = Vec::new();
+        for part in &self.parts {
+            if let PartData::FunctionCall { function_call } = &part.data {
+                calls.push(function_call.clone());
+            }
+        }
+        calls
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -134,12 +300,42 @@ pub struct NoRoleContent {
     parts: Vec<Part>, // A vector of Part objects
 }
 
+impl NoRoleContent {
+    pub fn get_parts(&self) -> &Vec<Part> {
+        &self.parts
+    }
+
+    pub fn get_parts_mut(&mut self) -> &mut Vec<Part> {
+        &mut self.parts
+    }
+
+    pub(crate) fn from_text(text: &str) -> Self {
+        NoRoleContent {
+            parts: vec![Part {
+                data: PartData::Text {
+                    text: text.to_string(),
+                },
+            }],
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Part {
     #[serde(flatten)] // This enables the union-like behavior for the different possible types
     data: PartData, // Union field that can be one of several types
 }
 
+impl Part {
+    pub fn get_data(&self) -> &PartData {
+        &self.data
+    }
+
+    pub fn get_data_mut(&mut self) -> &mut PartData {
+        &mut self.data
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Blob {
     mime_type: String,
@@ -161,22 +357,66 @@ pub struct FileData {
     file_uri: String, // File URI
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FunctionCall {
+    name: String,        // Name of the function to call
+    args: Option<Value>, // Function arguments as a JSON object
+}
+
+impl FunctionCall {
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_args(&self) -> Option<&Value> {
+        self.args.as_ref()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+pub struct FunctionResponse {
+    name: String,    // Name of the function that was called
+    response: Value, // Function result as a JSON object
+}
+
+impl FunctionResponse {
+    pub fn new(name: &str, response: Value) -> Self {
+        FunctionResponse {
+            name: name.to_string(),
+            response,
+        }
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_response(&self) -> &Value {
+        &self.response
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub(crate) struct PromptFeedback {
     block_reason: Option<BlockReason>, // Block reason, optional
-    safety_ratings: Vec<SafetyRating>, // A vector of SafetyRating objects
+    #[serde(default)]
+    safety_ratings: Vec<SafetyRating>, // A vector of SafetyRating objects, omitted by Vertex AI
 }
 
 impl PromptFeedback {
     pub(crate) fn get_block_reason(&self) -> Option<BlockReason> {
         self.block_reason.clone()
     }
+
+    pub(crate) fn get_safety_ratings(&self) -> &Vec<SafetyRating> {
+        &self.safety_ratings
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")] // Ensure enum variants match the JSON casing
-pub(crate) enum BlockReason {
+pub enum BlockReason {
     BlockReasonUnspecified, // Default value, unused
     Safety,                 // Blocked for safety reasons
     Other,                  // Blocked for unknown reasons
@@ -197,12 +437,26 @@ impl std::fmt::Display for BlockReason {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub(crate) struct SafetyRating {
+pub struct SafetyRating {
     category: Option<String>,    // The safety category
     probability: Option<String>, // The probability of the content being unsafe
     blocked: Option<bool>,       // Whether the content is blocked
 }
 
+impl SafetyRating {
+    pub fn get_category(&self) -> Option<&str> {
+        self.category.as_deref()
+    }
+
+    pub fn get_probability(&self) -> Option<&str> {
+        self.probability.as_deref()
+    }
+
+    pub fn is_blocked(&self) -> bool {
+        self.blocked.unwrap_or(false)
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct UsageMetadata {
@@ -259,7 +513,7 @@ pub struct File {
     error: Option<Status>,
     video_metadata: Option<VideoMetadata>,
     #[serde(skip)]
-    api_key: String,
+    client: Option<Client>,
 }
 
 impl File {
@@ -267,33 +521,38 @@ impl File {
         file_name: &str,
         bytes: Vec<u8>,
         mime_type: &str,
-        api_key: &str,
+        client: &Client,
     ) -> Result<Self, GemError> {
-        Self::upload(file_name, bytes, mime_type, api_key).await
+        Self::upload(file_name, bytes, mime_type, client).await
     }
 
     async fn upload(
         file_name: &str,
         buffer: Vec<u8>,
         mime_type: &str,
-        api_key: &str,
+        client: &Client,
     ) -> Result<Self, GemError> {
-        let num_bytes = buffer.len();
+        if let Backend::VertexAi { .. } = client.backend() {
+            return Err(GemError::FileError(
+                "Vertex AI does not support file uploads, use Cloud Storage URIs".to_string(),
+            ));
+        }
 
-        let client = reqwest::Client::new();
+        let num_bytes = buffer.len();
 
         let reserve_response = match client
-            .post("https://generativelanguage.googleapis.com/upload/v1beta/files")
-            .query(&[("key", api_key)])
-            .header("X-Goog-Upload-Protocol", "resumable")
-            .header("X-Goog-Upload-Command", "start")
-            .header("X-Goog-Upload-Header-Content-Length", num_bytes.to_string())
-            .header("X-Goog-Upload-Header-Content-Type", mime_type)
-            .header(header::CONTENT_TYPE, "application/json")
-            .json(&json!({
-                "file": { "display_name": file_name }
-            }))
-            .send()
+            .send(
+                client
+                    .request(Method::POST, &client.upload_endpoint("files"))
+                    .header("X-Goog-Upload-Protocol", "resumable")
+                    .header("X-Goog-Upload-Command", "start")
+                    .header("X-Goog-Upload-Header-Content-Length", num_bytes.to_string())
+                    .header("X-Goog-Upload-Header-Content-Type", mime_type)
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .json(&json!({
+                        "file": { "display_name": file_name }
+                    })),
+            )
             .await
         {
             Ok(response) => response,
@@ -313,13 +572,16 @@ impl File {
         };
 
         // Uploading the file's bytes
+        // The upload URL is already authorized, so the request is sent without credentials
         let upload_response = match client
-            .put(location)
-            .header("Content-Length", num_bytes.to_string())
-            .header("X-Goog-Upload-Offset", "0")
-            .header("X-Goog-Upload-Command", "upload, finalize")
-            .body(buffer)
-            .send()
+            .dispatch(
+                client
+                    .request(Method::PUT, location)
+                    .header("Content-Length", num_bytes.to_string())
+                    .header("X-Goog-Upload-Offset", "0")
+                    .header("X-Goog-Upload-Command", "upload, finalize")
+                    .body(buffer),
+            )
             .await
         {
             Ok(response) => response,
@@ -356,12 +618,7 @@ impl File {
         let mut timeout = 0;
         loop {
             let file_state = match client
-                .get(&format!(
-                    "https://generativelanguage.googleapis.com/v1beta/{}",
-                    file.name
-                ))
-                .query(&[("key", api_key)])
-                .send()
+                .send(client.request(Method::GET, &client.endpoint(&file.name)))
                 .await
             {
                 Ok(response) => response,
@@ -412,22 +669,22 @@ impl File {
             std::thread::sleep(std::time::Duration::from_secs(3));
         }
 
-        file.api_key = api_key.to_string();
+        file.client = Some(client.clone());
         Ok(file)
     }
 
     //TODO: Something with the API cause the cached files in cloud to change uri every time they are deleted
     async fn delete(self) -> Result<(), GemError> {
         log::info!("Deleting file: {:#?}", self);
-        if self.api_key == "" {
-            log::info!("API key not found: {:#?}", self.display_name);
-            return Err(GemError::FileError("API key not found".to_string()));
-        }
-        let client = reqwest::Client::new();
+        let client = match &self.client {
+            Some(client) => client,
+            None => {
+                log::info!("Client not found: {:#?}", self.display_name);
+                return Err(GemError::FileError("Client not found".to_string()));
+            }
+        };
         match client
-            .delete(self.uri)
-            .query(&[("key", self.api_key.clone())])
-            .send()
+            .send(client.request(Method::DELETE, &client.endpoint(&self.name)))
             .await
         {
             Ok(_) => {
@@ -442,29 +699,39 @@ impl File {
 #[derive(Debug)]
 pub struct FileManager {
     files: Mutex<HashMap<String, File>>,
-    api_key: String,
+    client: Client,
 }
 
 impl FileManager {
+    /// Creates a `FileManager` with its own client, using the default connection timeout and
+    /// no total timeout.
+    ///
+    /// Use `with_client` to share a session's client.
     pub fn new(api_key: &str) -> Self {
+        Self::with_client(&Client::without_timeout(api_key))
+    }
+
+    /// Creates a `FileManager` sharing the connection pool and configuration of `client`.
+    pub fn with_client(client: &Client) -> Self {
         Self {
             files: Mutex::new(HashMap::new()),
-            api_key: api_key.to_string(),
+            client: client.clone(),
         }
     }
 
+    /// Uploads `bytes` unless the same content was already uploaded, authenticating with the
+    /// manager's client.
     pub async fn add_file_from_bytes(
         &self,
         file_name: &str,
         bytes: Vec<u8>,
         mime_type: &str,
-        api_key: &str,
     ) -> Result<FileData, GemError> {
         let hash = sha256::digest(&bytes);
         match self.get_file(&hash).await {
             Some(file) => Ok(file),
             None => {
-                let file = File::new(file_name, bytes, mime_type, api_key).await?;
+                let file = File::new(file_name, bytes, mime_type, &self.client).await?;
                 let mime_type = file.mime_type.clone();
                 let file_uri = file.uri.clone();
                 let mut files = self.files.lock().await;
@@ -508,7 +775,7 @@ impl FileManager {
         match self.get_file(&hash).await {
             Some(file) => Ok(file),
             None => {
-                let file = File::new(file_name, buffer, &mime_type, &self.api_key).await?;
+                let file = File::new(file_name, buffer, &mime_type, &self.client).await?;
                 let mime_type = file.mime_type.clone();
                 let file_uri = file.uri.clone();
                 let mut files = self.files.lock().await;
@@ -563,20 +830,25 @@ impl FileManager {
     }
 
     pub async fn fetch_list(&mut self) -> Result<(), GemError> {
-        let client = reqwest::Client::new();
+        if let Backend::VertexAi { .. } = self.client.backend() {
+            return Err(GemError::FileError(
+                "Vertex AI does not support the files API, use Cloud Storage URIs".to_string(),
+            ));
+        }
+
         let mut files = Vec::new();
         let mut page_token: Option<String> = None;
 
         loop {
-            let mut request = client.get("https://generativelanguage.googleapis.com/v1beta/files");
+            let mut request = self
+                .client
+                .request(Method::GET, &self.client.endpoint("files"));
 
             if let Some(token) = &page_token {
-                request = request.query(&[("pageToken", token), ("key", &self.api_key)]);
-            } else {
-                request = request.query(&[("key", &self.api_key)]);
+                request = request.query(&[("pageToken", token)]);
             }
 
-            let response = match request.send().await {
+            let response = match self.client.send(request).await {
                 Ok(response) => response,
                 Err(e) => return Err(GemError::FileError(e.to_string())),
             };
@@ -615,7 +887,7 @@ impl FileManager {
 
         let mut files_map = self.files.lock().await;
         for mut file in files {
-            file.api_key = self.api_key.clone();
+            file.client = Some(self.client.clone());
             log::info!("File: {:#?}", file);
             files_map.insert(file.sha256_hash.clone(), file);
         }
@@ -643,6 +915,252 @@ impl FileManager {
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CachedContentUsageMetadata {
+    total_token_count: Option<i32>, // Total number of tokens that the cached content consumes
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedContent {
+    name: String, // Resource name, e.g. cachedContents/{id}
+    model: Option<String>,
+    display_name: Option<String>,
+    create_time: Option<String>,
+    update_time: Option<String>,
+    expire_time: Option<String>,
+    usage_metadata: Option<CachedContentUsageMetadata>,
+}
+
+impl CachedContent {
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_model(&self) -> Option<&str> {
+        self.model.as_deref()
+    }
+
+    pub fn get_display_name(&self) -> Option<&str> {
+        self.display_name.as_deref()
+    }
+
+    pub fn get_expire_time(&self) -> Option<&str> {
+        self.expire_time.as_deref()
+    }
+
+    pub fn get_total_token_count(&self) -> Option<i32> {
+        self.usage_metadata
+            .as_ref()
+            .and_then(|metadata| metadata.total_token_count)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct CreateCachedContentRequest {
+    model: String,                             // Required: models/{model}
+    contents: Vec<Content>,                    // Content to cache
+    system_instruction: Option<NoRoleContent>, // Optional: Developer set system instructions
+    ttl: String,                               // Time to live, e.g. "300s"
+    display_name: Option<String>,              // Optional: Human readable name
+}
+
+#[derive(Debug)]
+pub struct CacheManager {
+    client: Client,
+}
+
+impl CacheManager {
+    /// Creates a `CacheManager` with its own client, using the default connection timeout and
+    /// no total timeout.
+    pub fn new(api_key: &str) -> Self {
+        Self::with_client(&Client::without_timeout(api_key))
+    }
+
+    /// Creates a `CacheManager` sharing the connection pool and configuration of `client`.
+    pub fn with_client(client: &Client) -> Self {
+        Self {
+            client: client.clone(),
+        }
+    }
+
+    /// Caches the contents of `context` and the system instruction for `ttl`.
+    ///
+    /// Context caching only works with explicit model versions (e.g. `gemini-1.5-flash-001`),
+    /// and the cached content has to meet the API's minimum token count.
+    pub async fn create(
+        &self,
+        model: Models,
+        context: &Context,
+        system_instruction: Option<&str>,
+        ttl: std::time::Duration,
+        display_name: Option<&str>,
+    ) -> Result<CachedContent, GemError> {
+        let request = CreateCachedContentRequest {
+            model: self.client.model_resource(&model),
+            contents: context.contents.clone(),
+            system_instruction: system_instruction.map(|instruction| NoRoleContent {
+                parts: vec![Part {
+                    data: PartData::Text {
+                        text: instruction.to_string(),
+                    },
+                }],
+            }),
+            ttl: format!("{}s", ttl.as_secs()),
+            display_name: display_name.map(String::from),
+        };
+
+        let response = match self
+            .client
+            .send(
+                self.client
+                    .request(Method::POST, &self.client.endpoint("cachedContents"))
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .json(&request),
+            )
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => return Err(GemError::CacheError(e.to_string())),
+        };
+
+        Self::parse_response(response).await
+    }
+
+    pub async fn list(&self) -> Result<Vec<CachedContent>, GemError> {
+        let mut cached_contents = Vec::new();
+        let mut page_token: Option<String> = None;
+
+        loop {
+            let mut request = self
+                .client
+                .request(Method::GET, &self.client.endpoint("cachedContents"));
+
+            if let Some(token) = &page_token {
+                request = request.query(&[("pageToken", token)]);
+            }
+
+            let response = match self.client.send(request).await {
+                Ok(response) => response,
+                Err(e) => return Err(GemError::CacheError(e.to_string())),
+            };
+
+            let response_json: Value = Self::parse_response(response).await?;
+
+            // The field is omitted when there are no cached contents
+            if let Some(c) = response_json.get("cachedContents") {
+                match serde_json::from_value::<Vec<CachedContent>>(c.clone()) {
+                    Ok(mut new_contents) => cached_contents.append(&mut new_contents),
+                    Err(e) => return Err(GemError::CacheError(e.to_string())),
+                }
+            }
+
+            page_token = response_json
+                .get("nextPageToken")
+                .and_then(|t| t.as_str().map(String::from));
+            if page_token.is_none() {
+                break;
+            }
+        }
+
+        Ok(cached_contents)
+    }
+
+    /// Fetches a cached content by its resource name (`cachedContents/{id}`).
+    pub async fn get(&self, name: &str) -> Result<CachedContent, GemError> {
+        let response = match self
+            .client
+            .send(self.client.request(Method::GET, &self.resource_url(name)))
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => return Err(GemError::CacheError(e.to_string())),
+        };
+
+        Self::parse_response(response).await
+    }
+
+    pub async fn update_ttl(
+        &self,
+        name: &str,
+        ttl: std::time::Duration,
+    ) -> Result<CachedContent, GemError> {
+        let response = match self
+            .client
+            .send(
+                self.client
+                    .request(Method::PATCH, &self.resource_url(name))
+                    .query(&[("updateMask", "ttl")])
+                    .header(header::CONTENT_TYPE, "application/json")
+                    .json(&json!({ "ttl": format!("{}s", ttl.as_secs()) })),
+            )
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => return Err(GemError::CacheError(e.to_string())),
+        };
+
+        Self::parse_response(response).await
+    }
+
+    pub async fn delete(&self, name: &str) -> Result<(), GemError> {
+        let response = match self
+            .client
+            .send(
+                self.client
+                    .request(Method::DELETE, &self.resource_url(name)),
+            )
+            .await
+        {
+            Ok(response) => response,
+            Err(e) => return Err(GemError::CacheError(e.to_string())),
+        };
+
+        let _: Value = Self::parse_response(response).await?;
+        log::info!("Cached content deleted successfully: {}", name);
+        Ok(())
+    }
+
+    fn resource_url(&self, name: &str) -> String {
+        let id = name.trim_start_matches("cachedContents/");
+        self.client.endpoint(&format!("cachedContents/{}", id))
+    }
+
+    async fn parse_response<T: DeserializeOwned>(
+        response: reqwest::Response,
+    ) -> Result<T, GemError> {
+        let status_code = response.status();
+        let response_text = match response.text().await {
+            Ok(text) => text,
+            Err(e) => return Err(GemError::CacheError(e.to_string())),
+        };
+
+        if !status_code.is_success() {
+            return match serde_json::from_str::<Value>(&response_text)
+                .ok()
+                .and_then(|data| data.get("error").cloned())
+                .and_then(|error| serde_json::from_value::<Error>(error).ok())
+            {
+                Some(error) => Err(GemError::GeminiAPIError(error)),
+                None => Err(GemError::CacheError(format!(
+                    "Response error: {} (status code: {})",
+                    response_text, status_code
+                ))),
+            };
+        }
+
+        match serde_json::from_str::<T>(&response_text) {
+            Ok(data) => Ok(data),
+            Err(e) => {
+                log::error!("Cache error: {}, response: {}", e, response_text);
+                Err(GemError::CacheError(e.to_string()))
+            }
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct SafetySetting {
     category: HarmCategory,        // Enum for the harm category
@@ -663,6 +1181,49 @@ pub struct Error {
     code: i32,
     message: String,
     status: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    details: Vec<Value>, // Optional: google.rpc details, e.g. RetryInfo
+}
+
+impl Error {
+    /// Parses an error response, either wrapped in an `error` field or bare.
+    pub(crate) fn from_response(text: &str) -> Result<Error, serde_json::Error> {
+        #[derive(Deserialize)]
+        struct Envelope {
+            error: Error,
+        }
+
+        match serde_json::from_str::<Envelope>(text) {
+            Ok(envelope) => Ok(envelope.error),
+            Err(_) => serde_json::from_str::<Error>(text),
+        }
+    }
+
+    pub fn get_code(&self) -> i32 {
+        self.code
+    }
+
+    pub fn get_message(&self) -> &str {
+        &self.message
+    }
+
+    pub fn get_status(&self) -> &str {
+        &self.status
+    }
+
+    /// Returns the delay requested by a `google.rpc.RetryInfo` detail, if any.
+    pub fn get_retry_delay(&self) -> Option<std::time::Duration> {
+        self.details
+            .iter()
+            .filter(|detail| {
+                detail
+                    .get("@type")
+                    .and_then(|t| t.as_str())
+                    .is_some_and(|t| t.ends_with("google.rpc.RetryInfo"))
+            })
+            .find_map(|detail| detail.get("retryDelay").and_then(|d| d.as_str()))
+            .and_then(parse_duration)
+    }
 }
 
 impl std::fmt::Display for Error {
@@ -682,28 +1243,172 @@ pub enum HarmBlockThreshold {
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-pub(crate) struct GenerationConfig {
-    stop_sequences: Option<Vec<String>>, // Optional: Up to 5 stop sequences
-    response_mime_type: Option<String>, // Optional: MIME type of the response (e.g., text/plain, application/json)
-    max_output_tokens: Option<u32>,     // Optional: Max tokens for the response up to 8192
-    temperature: Option<f32>,           // Optional: Controls randomness of the output [0.0, 2.0]
-    top_p: Option<f32>, // Optional: Maximum cumulative probability for nucleus sampling
-    top_k: Option<u32>, // Optional: Maximum number of tokens to consider for top-k sampling
+pub struct FunctionDeclaration {
+    name: String,              // Function name, a-z, A-Z, 0-9, underscores and dashes
+    description: String,       // Brief description of what the function does
+    parameters: Option<Value>, // Optional: OpenAPI-subset JSON schema of the arguments
 }
 
+impl FunctionDeclaration {
+    pub fn new(name: &str, description: &str, parameters: Option<Value>) -> Self {
+        FunctionDeclaration {
+            name: name.to_string(),
+            description: description.to_string(),
+            parameters,
+        }
+    }
+
+    #[cfg(feature = "schema")]
+    pub fn with_parameters<T: GeminiSchema>(name: &str, description: &str) -> Self {
+        Self::new(name, description, Some(T::schema()))
+    }
+
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct Tools {
+    function_declarations: Vec<FunctionDeclaration>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")] // To match the JSON format
+pub enum FunctionCallingMode {
+    Auto, // The model decides between a function call and a natural language response
+    Any,  // The model is constrained to always predict a function call
+    None, // The model will not predict any function call
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct FunctionCallingConfig {
+    mode: FunctionCallingMode,
+    allowed_function_names: Option<Vec<String>>, // Optional: Only used with `Any` mode
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct ToolConfig {
+    function_calling_config: FunctionCallingConfig,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub(crate) struct GenerationConfig {
+    stop_sequences: Option<Vec<String>>, // Optional: Up to 5 stop sequences
+    response_mime_type: Option<String>, // Optional: MIME type of the response (e.g., text/plain, application/json)
+    response_schema: Option<Value>, // Optional: OpenAPI-subset schema of the response, requires application/json
+    max_output_tokens: Option<u32>,     // Optional: Max tokens for the response up to 8192
+    temperature: Option<f32>,           // Optional: Controls randomness of the output [0.0, 2.0]
+    top_p: Option<f32>, // Optional: Maximum cumulative probability for nucleus sampling
+    top_k: Option<u32>, // Optional: Maximum number of tokens to consider for top-k sampling
+    candidate_count: Option<u32>, // Optional: Number of generated responses to return
+    seed: Option<i32>,  // Optional: Seed used in decoding, random if unset
+    presence_penalty: Option<f32>, // Optional: Penalty for tokens that already appeared in the response
+    frequency_penalty: Option<f32>, // Optional: Penalty scaled by how often a token appeared
+    response_logprobs: Option<bool>, // Optional: Export the logprobs results in the response
+    logprobs: Option<u32>,         // Optional: Number of top logprobs per token [1, 20]
+}
+
+impl GenerationConfig {
+    /// Returns a copy of `self` where every field set in `overrides` is replaced.
+    fn merge(&self, overrides: &GenerationConfig) -> GenerationConfig {
+        GenerationConfig {
+            stop_sequences: overrides
+                .stop_sequences
+                .clone()
+                .or_else(|| self.stop_sequences.clone()),
+            response_mime_type: overrides
+                .response_mime_type
+                .clone()
+                .or_else(|| self.response_mime_type.clone()),
+            response_schema: overrides
+                .response_schema
+                .clone()
+                .or_else(|| self.response_schema.clone()),
+            max_output_tokens: overrides.max_output_tokens.or(self.max_output_tokens),
+            temperature: overrides.temperature.or(self.temperature),
+            top_p: overrides.top_p.or(self.top_p),
+            top_k: overrides.top_k.or(self.top_k),
+            candidate_count: overrides.candidate_count.or(self.candidate_count),
+            seed: overrides.seed.or(self.seed),
+            presence_penalty: overrides.presence_penalty.or(self.presence_penalty),
+            frequency_penalty: overrides.frequency_penalty.or(self.frequency_penalty),
+            response_logprobs: overrides.response_logprobs.or(self.response_logprobs),
+            logprobs: overrides.logprobs.or(self.logprobs),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
 pub struct Settings {
     safety_settings: Option<Vec<SafetySetting>>,
     generation_config: Option<GenerationConfig>,
     system_instruction: Option<String>,
+    function_declarations: Option<Vec<FunctionDeclaration>>,
+    tool_config: Option<ToolConfig>,
+    cached_content: Option<String>,
+    deadline: Option<std::time::Duration>, // Not sent, bounds the whole call including its stream
+    cancellation_token: Option<CancellationToken>, // Not sent, cancels the call
 }
 
 impl Settings {
+    /// Returns a new `SettingsBuilder` for creating `Settings` fluently.
+    pub fn builder() -> SettingsBuilder {
+        SettingsBuilder(Settings::new())
+    }
+
     pub fn new() -> Self {
         Settings {
             safety_settings: None,
             generation_config: None,
             system_instruction: None,
+            function_declarations: None,
+            tool_config: None,
+            cached_content: None,
+            deadline: None,
+            cancellation_token: None,
+        }
+    }
+
+    /// Returns a copy of `self` where every field set in `overrides` is replaced,
+    /// field by field for the generation config.
+    ///
+    /// This is how `GemSession` applies per-call settings on top of its defaults.
+    pub fn merge(&self, overrides: &Settings) -> Settings {
+        Settings {
+            safety_settings: overrides
+                .safety_settings
+                .clone()
+                .or_else(|| self.safety_settings.clone()),
+            generation_config: match (&self.generation_config, &overrides.generation_config) {
+                (Some(config), Some(overrides)) => Some(config.merge(overrides)),
+                (config, overrides) => overrides.clone().or_else(|| config.clone()),
+            },
+            system_instruction: overrides
+                .system_instruction
+                .clone()
+                .or_else(|| self.system_instruction.clone()),
+            function_declarations: overrides
+                .function_declarations
+                .clone()
+                .or_else(|| self.function_declarations.clone()),
+            tool_config: overrides
+                .tool_config
+                .clone()
+                .or_else(|| self.tool_config.clone()),
+            cached_content: overrides
+                .cached_content
+                .clone()
+                .or_else(|| self.cached_content.clone()),
+            deadline: overrides.deadline.or(self.deadline),
+            cancellation_token: overrides
+                .cancellation_token
+                .clone()
+                .or_else(|| self.cancellation_token.clone()),
         }
     }
 
@@ -728,6 +1433,7 @@ impl Settings {
         ]);
     }
 
+    #[deprecated(note = "use `Settings::builder` or the individual setters instead")]
     pub fn set_advance_settings(
         &mut self,
         stop_sequences: Option<Vec<String>>,
@@ -738,62 +1444,312 @@ impl Settings {
         top_k: Option<u32>,
     ) {
         self.generation_config = Some(GenerationConfig {
-            stop_sequences: stop_sequences,
-            response_mime_type: response_mime_type,
-            max_output_tokens: max_output_tokens,
-            temperature: temperature,
-            top_p: top_p,
-            top_k: top_k,
+            stop_sequences,
+            response_mime_type,
+            max_output_tokens,
+            temperature,
+            top_p,
+            top_k,
+            ..Default::default()
         });
     }
 
+    /// Returns the generation config, creating an empty one if needed.
+    fn generation_config_mut(&mut self) -> &mut GenerationConfig {
+        self.generation_config
+            .get_or_insert_with(GenerationConfig::default)
+    }
+
+    pub fn set_stop_sequences(&mut self, stop_sequences: Vec<String>) {
+        self.generation_config_mut().stop_sequences = Some(stop_sequences);
+    }
+
+    pub fn set_response_mime_type(&mut self, response_mime_type: &str) {
+        self.generation_config_mut().response_mime_type = Some(response_mime_type.to_string());
+    }
+
     pub fn set_temperature(&mut self, temperature: f32) {
-        match &mut self.generation_config {
-            Some(config) => config.temperature = Some(temperature),
-            None => {
-                self.generation_config = Some(GenerationConfig {
-                    stop_sequences: None,
-                    response_mime_type: None,
-                    max_output_tokens: None,
-                    temperature: Some(temperature),
-                    top_p: None,
-                    top_k: None,
-                });
-            }
-        }
+        self.generation_config_mut().temperature = Some(temperature);
     }
 
     pub fn set_max_output_tokens(&mut self, max_output_tokens: u32) {
-        match &mut self.generation_config {
-            Some(config) => config.max_output_tokens = Some(max_output_tokens),
-            None => {
-                self.generation_config = Some(GenerationConfig {
-                    stop_sequences: None,
-                    response_mime_type: None,
-                    max_output_tokens: Some(max_output_tokens),
-                    temperature: None,
-                    top_p: None,
-                    top_k: None,
-                });
-            }
-        }
+        self.generation_config_mut().max_output_tokens = Some(max_output_tokens);
+    }
+
+    pub fn set_top_p(&mut self, top_p: f32) {
+        self.generation_config_mut().top_p = Some(top_p);
+    }
+
+    pub fn set_top_k(&mut self, top_k: u32) {
+        self.generation_config_mut().top_k = Some(top_k);
+    }
+
+    pub fn set_candidate_count(&mut self, candidate_count: u32) {
+        self.generation_config_mut().candidate_count = Some(candidate_count);
+    }
+
+    pub fn set_seed(&mut self, seed: i32) {
+        self.generation_config_mut().seed = Some(seed);
+    }
+
+    pub fn set_presence_penalty(&mut self, presence_penalty: f32) {
+        self.generation_config_mut().presence_penalty = Some(presence_penalty);
+    }
+
+    pub fn set_frequency_penalty(&mut self, frequency_penalty: f32) {
+        self.generation_config_mut().frequency_penalty = Some(frequency_penalty);
+    }
+
+    pub fn set_response_logprobs(&mut self, response_logprobs: bool) {
+        self.generation_config_mut().response_logprobs = Some(response_logprobs);
+    }
+
+    /// Sets the number of top logprobs returned for each token, requires `response_logprobs`.
+    pub fn set_logprobs(&mut self, logprobs: u32) {
+        self.generation_config_mut().logprobs = Some(logprobs);
+    }
+
+    pub fn set_response_schema(&mut self, schema: Value) {
+        let config = self.generation_config_mut();
+        config.response_mime_type = Some("application/json".to_string());
+        config.response_schema = Some(schema);
+    }
+
+    #[cfg(feature = "schema")]
+    pub fn set_response_schema_for<T: GeminiSchema>(&mut self) {
+        self.set_response_schema(T::schema());
+    }
+
+    pub(crate) fn set_json_response(&mut self) {
+        self.generation_config_mut().response_mime_type = Some("application/json".to_string());
     }
 
     pub fn set_system_instruction(&mut self, instruction: &str) {
         self.system_instruction = Some(instruction.to_string());
     }
+
+    /// Points the requests at a cached content (`cachedContents/{id}`) created with
+    /// `CacheManager`. The system instruction and tools of the cache are used, so they
+    /// should not be set again in these settings.
+    pub fn set_cached_content(&mut self, name: &str) {
+        self.cached_content = Some(name.to_string());
+    }
+
+    /// Sets the time a call may take, including retries and reading its stream, before
+    /// failing with `GemError::DeadlineExceeded`. Unlike the session's timeouts, it is
+    /// not sent and applies to this call only.
+    pub fn set_deadline(&mut self, deadline: std::time::Duration) {
+        self.deadline = Some(deadline);
+    }
+
+    pub fn get_deadline(&self) -> Option<std::time::Duration> {
+        self.deadline
+    }
+
+    /// Sets a token cancelling the call when `CancellationToken::cancel` is called, e.g. by
+    /// a "stop generating" button. The call then fails with `GemError::Cancelled`.
+    pub fn set_cancellation_token(&mut self, token: CancellationToken) {
+        self.cancellation_token = Some(token);
+    }
+
+    pub fn get_cancellation_token(&self) -> Option<&CancellationToken> {
+        self.cancellation_token.as_ref()
+    }
+
+    pub fn add_function_declaration(&mut self, declaration: FunctionDeclaration) {
+        match &mut self.function_declarations {
+            Some(declarations) => declarations.push(declaration),
+            None => self.function_declarations = Some(vec![declaration]),
+        }
+    }
+
+    pub fn set_function_declarations(&mut self, declarations: Vec<FunctionDeclaration>) {
+        self.function_declarations = Some(declarations);
+    }
+
+    pub fn set_function_calling_mode(
+        &mut self,
+        mode: FunctionCallingMode,
+        allowed_function_names: Option<Vec<String>>,
+    ) {
+        self.tool_config = Some(ToolConfig {
+            function_calling_config: FunctionCallingConfig {
+                mode,
+                allowed_function_names,
+            },
+        });
+    }
+}
+
+/// Builder for creating `Settings` with chained calls.
+///
+/// # Example
+///
+/// ```
+/// use gem_rs::types::{HarmBlockThreshold, Settings};
+///
+/// let settings = Settings::builder()
+///     .all_safety_settings(HarmBlockThreshold::BlockNone)
+///     .temperature(0.7)
+///     .candidate_count(2)
+///     .seed(42)
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct SettingsBuilder(Settings);
+
+impl SettingsBuilder {
+    pub fn all_safety_settings(mut self, threshold: HarmBlockThreshold) -> Self {
+        self.0.set_all_safety_settings(threshold);
+        self
+    }
+
+    pub fn system_instruction(mut self, instruction: &str) -> Self {
+        self.0.set_system_instruction(instruction);
+        self
+    }
+
+    pub fn stop_sequences(mut self, stop_sequences: Vec<String>) -> Self {
+        self.0.set_stop_sequences(stop_sequences);
+        self
+    }
+
+    pub fn response_mime_type(mut self, response_mime_type: &str) -> Self {
+        self.0.set_response_mime_type(response_mime_type);
+        self
+    }
+
+    pub fn response_schema(mut self, schema: Value) -> Self {
+        self.0.set_response_schema(schema);
+        self
+    }
+
+    #[cfg(feature = "schema")]
+    pub fn response_schema_for<T: GeminiSchema>(mut self) -> Self {
+        self.0.set_response_schema_for::<T>();
+        self
+    }
+
+    pub fn max_output_tokens(mut self, max_output_tokens: u32) -> Self {
+        self.0.set_max_output_tokens(max_output_tokens);
+        self
+    }
+
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.0.set_temperature(temperature);
+        self
+    }
+
+    pub fn top_p(mut self, top_p: f32) -> Self {
+        self.0.set_top_p(top_p);
+        self
+    }
+
+    pub fn top_k(mut self, top_k: u32) -> Self {
+        self.0.set_top_k(top_k);
+        self
+    }
+
+    pub fn candidate_count(mut self, candidate_count: u32) -> Self {
+        self.0.set_candidate_count(candidate_count);
+        self
+    }
+
+    pub fn seed(mut self, seed: i32) -> Self {
+        self.0.set_seed(seed);
+        self
+    }
+
+    pub fn presence_penalty(mut self, presence_penalty: f32) -> Self {
+        self.0.set_presence_penalty(presence_penalty);
+        self
+    }
+
+    pub fn frequency_penalty(mut self, frequency_penalty: f32) -> Self {
+        self.0.set_frequency_penalty(frequency_penalty);
+        self
+    }
+
+    pub fn response_logprobs(mut self, response_logprobs: bool) -> Self {
+        self.0.set_response_logprobs(response_logprobs);
+        self
+    }
+
+    pub fn logprobs(mut self, logprobs: u32) -> Self {
+        self.0.set_logprobs(logprobs);
+        self
+    }
+
+    pub fn function_declaration(mut self, declaration: FunctionDeclaration) -> Self {
+        self.0.add_function_declaration(declaration);
+        self
+    }
+
+    pub fn function_calling_mode(
+        mut self,
+        mode: FunctionCallingMode,
+        allowed_function_names: Option<Vec<String>>,
+    ) -> Self {
+        self.0
+            .set_function_calling_mode(mode, allowed_function_names);
+        self
+    }
+
+    pub fn cached_content(mut self, name: &str) -> Self {
+        self.0.set_cached_content(name);
+        self
+    }
+
+    pub fn deadline(mut self, deadline: std::time::Duration) -> Self {
+        self.0.set_deadline(deadline);
+        self
+    }
+
+    pub fn cancellation_token(mut self, token: CancellationToken) -> Self {
+        self.0.set_cancellation_token(token);
+        self
+    }
+
+    pub fn build(self) -> Settings {
+        self.0
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
-pub(crate) struct GenerateContentRequest {
+pub struct GenerateContentRequest {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>, // Only required when nested in a countTokens request, models/{model}
     contents: Vec<Content>, // Required: List of content objects (conversation history and latest request)
     safety_settings: Option<Vec<SafetySetting>>, // Optional: Safety settings to block unsafe content
     generation_config: Option<GenerationConfig>, // Optional: Configuration for model generation
     system_instruction: Option<NoRoleContent>,   // Optional: Developer set system instructions
+    tools: Option<Vec<Tools>>, // Optional: Functions the model may call
+    tool_config: Option<ToolConfig>, // Optional: Function calling configuration
+    cached_content: Option<String>, // Optional: Cached content used as a prefix, cachedContents/{id}
 }
 
 impl GenerateContentRequest {
+    pub fn get_contents(&self) -> &Vec<Content> {
+        &self.contents
+    }
+
+    pub fn get_contents_mut(&mut self) -> &mut Vec<Content> {
+        &mut self.contents
+    }
+
+    pub fn get_system_instruction(&self) -> Option<&NoRoleContent> {
+        self.system_instruction.as_ref()
+    }
+
+    pub fn get_system_instruction_mut(&mut self) -> Option<&mut NoRoleContent> {
+        self.system_instruction.as_mut()
+    }
+
+    pub fn get_cached_content(&self) -> Option<&str> {
+        self.cached_content.as_deref()
+    }
+
     fn new(
         context: &Context,
         config: Option<GenerationConfig>,
@@ -801,6 +1757,7 @@ impl GenerateContentRequest {
         system_instruction: Option<NoRoleContent>,
     ) -> Self {
         GenerateContentRequest {
+            model: None,
             contents: context.contents.clone(),
             safety_settings: match safety {
                 Some(s) => Some(s),
@@ -828,17 +1785,195 @@ impl GenerateContentRequest {
                 None => Some(GenerationConfig {
                     max_output_tokens: Some(8192),
                     temperature: Some(1.0),
-                    response_mime_type: None,
-                    stop_sequences: None,
-                    top_k: None,
-                    top_p: None,
+                    ..Default::default()
                 }),
             },
             system_instruction,
+            tools: None,
+            tool_config: None,
+            cached_content: None,
         }
     }
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ModelInfo {
+    name: String, // Resource name, e.g. models/gemini-1.5-flash-002
+    base_model_id: Option<String>,
+    version: Option<String>,
+    display_name: Option<String>,
+    description: Option<String>,
+    input_token_limit: Option<u32>,  // Maximum number of input tokens
+    output_token_limit: Option<u32>, // Maximum number of output tokens
+    #[serde(default)]
+    supported_generation_methods: Vec<String>, // e.g. generateContent, countTokens
+    temperature: Option<f32>,        // Default temperature
+    max_temperature: Option<f32>,    // Maximum temperature the model accepts
+    top_p: Option<f32>,              // Default nucleus sampling
+    top_k: Option<u32>,              // Default top-k sampling, absent if not supported
+}
+
+impl ModelInfo {
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn get_display_name(&self) -> Option<&str> {
+        self.display_name.as_deref()
+    }
+
+    pub fn get_description(&self) -> Option<&str> {
+        self.description.as_deref()
+    }
+
+    pub fn get_version(&self) -> Option<&str> {
+        self.version.as_deref()
+    }
+
+    pub fn get_input_token_limit(&self) -> Option<u32> {
+        self.input_token_limit
+    }
+
+    pub fn get_output_token_limit(&self) -> Option<u32> {
+        self.output_token_limit
+    }
+
+    pub fn get_supported_generation_methods(&self) -> &Vec<String> {
+        &self.supported_generation_methods
+    }
+
+    pub fn supports(&self, method: &str) -> bool {
+        self.supported_generation_methods
+            .iter()
+            .any(|supported| supported == method)
+    }
+
+    pub fn get_temperature(&self) -> Option<f32> {
+        self.temperature
+    }
+
+    pub fn get_max_temperature(&self) -> Option<f32> {
+        self.max_temperature
+    }
+
+    pub fn get_top_p(&self) -> Option<f32> {
+        self.top_p
+    }
+
+    pub fn get_top_k(&self) -> Option<u32> {
+        self.top_k
+    }
+
+    /// Returns the model as a `Models` value that can be used with `GemSessionBuilder`.
+    pub fn to_model(&self) -> Models {
+        Models::Custom(self.name.clone())
+    }
+
+    /// Checks the settings against the limits of the model.
+    pub fn validate(&self, settings: &Settings) -> Result<(), GemError> {
+        if !self.supports("generateContent") {
+            return Err(GemError::InvalidSettings(format!(
+                "{} does not support generateContent",
+                self.name
+            )));
+        }
+
+        let config = match &settings.generation_config {
+            Some(config) => config,
+            None => return Ok(()),
+        };
+
+        if let (Some(temperature), Some(max)) = (config.temperature, self.max_temperature) {
+            if temperature < 0.0 || temperature > max {
+                return Err(GemError::InvalidSettings(format!(
+                    "temperature {} is outside of [0.0, {}]",
+                    temperature, max
+                )));
+            }
+        }
+
+        if let (Some(tokens), Some(limit)) = (config.max_output_tokens, self.output_token_limit) {
+            if tokens > limit {
+                return Err(GemError::InvalidSettings(format!(
+                    "max_output_tokens {} exceeds the output limit of {}",
+                    tokens, limit
+                )));
+            }
+        }
+
+        if let Some(top_p) = config.top_p {
+            if !(0.0..=1.0).contains(&top_p) {
+                return Err(GemError::InvalidSettings(format!(
+                    "top_p {} is outside of [0.0, 1.0]",
+                    top_p
+                )));
+            }
+        }
+
+        if config.top_k.is_some() && self.top_k.is_none() {
+            return Err(GemError::InvalidSettings(format!(
+                "{} does not support top_k sampling",
+                self.name
+            )));
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub(crate) enum CountTokensRequest {
+    GenerativeLanguage {
+        #[serde(rename = "generateContentRequest")]
+        generate_content_request: GenerateContentRequest, // Counts everything the request would send
+    },
+    // Vertex AI takes the fields at the top level, and rejects the other request fields
+    #[serde(rename_all = "camelCase")]
+    VertexAi {
+        contents: Vec<Content>,
+        system_instruction: Option<NoRoleContent>,
+        tools: Option<Vec<Tools>>,
+        generation_config: Option<GenerationConfig>,
+    },
+}
+
+impl CountTokensRequest {
+    pub(crate) fn new(model: &Models, mut request: GenerateContentRequest) -> Self {
+        request.model = Some(format!("models/{}", model.to_string()));
+        CountTokensRequest::GenerativeLanguage {
+            generate_content_request: request,
+        }
+    }
+
+    pub(crate) fn vertex_ai(request: GenerateContentRequest) -> Self {
+        CountTokensRequest::VertexAi {
+            contents: request.contents,
+            system_instruction: request.system_instruction,
+            tools: request.tools,
+            generation_config: request.generation_config,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CountTokensResponse {
+    total_tokens: i32, // Number of tokens the model tokenizes the prompt into
+    cached_content_token_count: Option<i32>, // Number of tokens in the cached part of the prompt
+}
+
+impl CountTokensResponse {
+    pub fn get_total_tokens(&self) -> i32 {
+        self.total_tokens
+    }
+
+    pub fn get_cached_content_token_count(&self) -> Option<i32> {
+        self.cached_content_token_count
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Context {
     contents: Vec<Content>,
@@ -917,8 +2052,24 @@ impl Context {
         });
     }
 
+    pub(crate) fn push_function_responses(&mut self, responses: Vec<FunctionResponse>) {
+        self.contents.push(Content {
+            role: Some(Role::User),
+            parts: responses
+                .into_iter()
+                .map(|function_response| Part {
+                    data: PartData::FunctionResponse { function_response },
+                })
+                .collect(),
+        });
+    }
+
+    pub(crate) fn push_content(&mut self, content: Content) {
+        self.contents.push(content);
+    }
+
     pub(crate) fn build(&self, settings: &Settings) -> GenerateContentRequest {
-        GenerateContentRequest::new(
+        let mut request = GenerateContentRequest::new(
             self,
             settings.generation_config.clone(),
             settings.safety_settings.clone(),
@@ -932,7 +2083,18 @@ impl Context {
                 }),
                 None => None,
             },
-        )
+        );
+        request.tools = settings
+            .function_declarations
+            .as_ref()
+            .map(|declarations| {
+                vec![Tools {
+                    function_declarations: declarations.clone(),
+                }]
+            });
+        request.tool_config = settings.tool_config.clone();
+        request.cached_content = settings.cached_content.clone();
+        request
     }
 
     pub(crate) fn clear(&mut self) {
@@ -960,6 +2122,45 @@ mod tests {
 
     use super::*;
 
+    #[test]
+    fn test_deserialize_vertex_ai_response() {
+        let json_data = r#"
+        {
+            "candidates": [
+                {
+                    "content": { "role": "model", "parts": [{ "text": "Hello" }] },
+                    "finishReason": "IMAGE_SAFETY",
+                    "safetyRatings": [
+                        {
+                            "category": "HARM_CATEGORY_HATE_SPEECH",
+                            "probability": "NEGLIGIBLE",
+                            "probabilityScore": 0.05,
+                            "severity": "HARM_SEVERITY_NEGLIGIBLE",
+                            "severityScore": 0.04
+                        }
+                    ]
+                }
+            ],
+            "usageMetadata": { "promptTokenCount": 2, "candidatesTokenCount": 1, "totalTokenCount": 3 },
+            "modelVersion": "gemini-1.5-flash-002",
+            "createTime": "2024-10-01T10:00:00.000000Z",
+            "responseId": "abc"
+        }
+        "#;
+
+        let response: GenerateContentResponse = serde_json::from_str(json_data).unwrap();
+        assert_eq!(response.get_candidates()[0].get_text().unwrap(), "Hello");
+        assert_eq!(
+            response.get_candidates()[0].finish_reason,
+            Some(FinishReason::Unknown)
+        );
+
+        // Blocked prompts have no candidates
+        let response: GenerateContentResponse =
+            serde_json::from_str(r#"{ "promptFeedback": { "blockReason": "SAFETY" } }"#).unwrap();
+        assert!(response.get_candidates().is_empty());
+    }
+
     #[test]
     fn test_deserialize_generate_content_response() {
         let json_data = r#"
@@ -1035,4 +2236,246 @@ mod tests {
         assert_eq!(usage_metadata.candidates_token_count.unwrap(), 10);
         assert_eq!(usage_metadata.total_token_count.unwrap(), 18);
     }
+
+    #[test]
+    fn test_function_call_round_trip() {
+        let json_data = r#"
+        {
+            "candidates": [
+                {
+                    "content": {
+                        "parts": [
+                            {
+                                "functionCall": {
+                                    "name": "get_weather",
+                                    "args": { "city": "Riyadh" }
+                                }
+                            }
+                        ],
+                        "role": "model"
+                    },
+                    "finishReason": "STOP"
+                }
+            ]
+        }
+        "#;
+
+        let response: GenerateContentResponse = serde_json::from_str(json_data).unwrap();
+        let calls = response.get_function_calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0].get_name(), "get_weather");
+        assert_eq!(calls[0].get_args().unwrap()["city"], "Riyadh");
+        assert!(response.get_results().is_empty());
+
+        let mut context = Context::new();
+        context.push_content(response.candidates[0].content.clone().unwrap());
+        context.push_function_responses(vec![FunctionResponse::new(
+            "get_weather",
+            json!({ "temperature": 41 }),
+        )]);
+
+        let mut settings = Settings::new();
+        settings.add_function_declaration(FunctionDeclaration::new(
+            "get_weather",
+            "Returns the current weather of a city",
+            Some(json!({
+                "type": "OBJECT",
+                "properties": { "city": { "type": "STRING" } },
+                "required": ["city"]
+            })),
+        ));
+        settings.set_function_calling_mode(FunctionCallingMode::Auto, None);
+
+        let request = serde_json::to_value(context.build(&settings)).unwrap();
+        assert_eq!(
+            request["contents"][0]["parts"][0]["functionCall"]["name"],
+            "get_weather"
+        );
+        assert_eq!(
+            request["contents"][1]["parts"][0]["functionResponse"]["response"]["temperature"],
+            41
+        );
+        assert_eq!(
+            request["tools"][0]["functionDeclarations"][0]["name"],
+            "get_weather"
+        );
+        assert_eq!(
+            request["toolConfig"]["functionCallingConfig"]["mode"],
+            "AUTO"
+        );
+    }
+
+    #[test]
+    fn test_response_schema_request() {
+        let mut settings = Settings::new();
+        settings.set_temperature(0.5);
+        settings.set_response_schema(json!({
+            "type": "ARRAY",
+            "items": { "type": "STRING" }
+        }));
+
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        let config = &request["generationConfig"];
+        assert_eq!(config["responseMimeType"], "application/json");
+        assert_eq!(config["responseSchema"]["items"]["type"], "STRING");
+        assert_eq!(config["temperature"], 0.5);
+    }
+
+    #[test]
+    fn test_cached_content() {
+        let json_data = r#"
+        {
+            "name": "cachedContents/abc123",
+            "model": "models/gemini-1.5-flash-001",
+            "createTime": "2024-09-01T10:00:00Z",
+            "updateTime": "2024-09-01T10:00:00Z",
+            "expireTime": "2024-09-01T10:05:00Z",
+            "usageMetadata": { "totalTokenCount": 42000 }
+        }
+        "#;
+
+        let cached: CachedContent = serde_json::from_str(json_data).unwrap();
+        assert_eq!(cached.get_name(), "cachedContents/abc123");
+        assert_eq!(cached.get_total_token_count(), Some(42000));
+        assert_eq!(
+            CacheManager::new("X").resource_url(cached.get_name()),
+            "https://generativelanguage.googleapis.com/v1beta/cachedContents/abc123"
+        );
+
+        let mut settings = Settings::new();
+        settings.set_cached_content(cached.get_name());
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        assert_eq!(request["cachedContent"], "cachedContents/abc123");
+    }
+
+    #[test]
+    fn test_count_tokens_request() {
+        let mut context = Context::new();
+        context.push_message(None, "Hello".to_string());
+
+        let request =
+            CountTokensRequest::new(&Models::Gemini15Flash, context.build(&Settings::new()));
+        let request = serde_json::to_value(request).unwrap();
+        assert_eq!(
+            request["generateContentRequest"]["model"],
+            "models/gemini-1.5-flash"
+        );
+        assert_eq!(
+            request["generateContentRequest"]["contents"][0]["parts"][0]["text"],
+            "Hello"
+        );
+
+        let request = CountTokensRequest::vertex_ai(context.build(&Settings::new()));
+        let request = serde_json::to_value(request).unwrap();
+        assert_eq!(request["contents"][0]["parts"][0]["text"], "Hello");
+        assert!(request.get("safetySettings").is_none());
+
+        let response: CountTokensResponse =
+            serde_json::from_str(r#"{ "totalTokens": 31 }"#).unwrap();
+        assert_eq!(response.get_total_tokens(), 31);
+        assert_eq!(response.get_cached_content_token_count(), None);
+    }
+
+    #[test]
+    fn test_model_info_validate() {
+        let json_data = r#"
+        {
+            "name": "models/gemini-1.5-flash-002",
+            "version": "002",
+            "displayName": "Gemini 1.5 Flash 002",
+            "inputTokenLimit": 1000000,
+            "outputTokenLimit": 8192,
+            "supportedGenerationMethods": ["generateContent", "countTokens"],
+            "temperature": 1,
+            "maxTemperature": 2,
+            "topP": 0.95,
+            "topK": 40
+        }
+        "#;
+
+        let info: ModelInfo = serde_json::from_str(json_data).unwrap();
+        assert_eq!(info.get_input_token_limit(), Some(1000000));
+        assert_eq!(info.to_model().to_string(), "gemini-1.5-flash-002");
+
+        let mut settings = Settings::new();
+        settings.set_temperature(1.5);
+        assert!(info.validate(&settings).is_ok());
+
+        settings.set_max_output_tokens(16384);
+        assert!(matches!(
+            info.validate(&settings),
+            Err(GemError::InvalidSettings(_))
+        ));
+    }
+
+    #[test]
+    fn test_settings_merge() {
+        let mut defaults = Settings::new();
+        defaults.set_all_safety_settings(HarmBlockThreshold::BlockOnlyHigh);
+        defaults.set_temperature(0.2);
+        defaults.set_max_output_tokens(1024);
+        defaults.set_system_instruction("Be brief");
+
+        let mut overrides = Settings::new();
+        overrides.set_temperature(1.5);
+
+        let request =
+            serde_json::to_value(Context::new().build(&defaults.merge(&overrides))).unwrap();
+        assert_eq!(request["generationConfig"]["temperature"], 1.5);
+        assert_eq!(request["generationConfig"]["maxOutputTokens"], 1024);
+        assert_eq!(request["safetySettings"][0]["threshold"], "BLOCK_ONLY_HIGH");
+        assert_eq!(request["systemInstruction"]["parts"][0]["text"], "Be brief");
+    }
+
+    #[test]
+    fn test_settings_builder() {
+        let settings = Settings::builder()
+            .temperature(0.7)
+            .top_k(40)
+            .candidate_count(2)
+            .seed(42)
+            .presence_penalty(0.5)
+            .frequency_penalty(-0.5)
+            .response_logprobs(true)
+            .logprobs(3)
+            .build();
+
+        let request = serde_json::to_value(Context::new().build(&settings)).unwrap();
+        let config = &request["generationConfig"];
+        assert_eq!(config["temperature"], 0.7f32 as f64);
+        assert_eq!(config["topK"], 40);
+        assert_eq!(config["candidateCount"], 2);
+        assert_eq!(config["seed"], 42);
+        assert_eq!(config["presencePenalty"], 0.5);
+        assert_eq!(config["frequencyPenalty"], -0.5);
+        assert_eq!(config["responseLogprobs"], true);
+        assert_eq!(config["logprobs"], 3);
+        assert!(config["maxOutputTokens"].is_null());
+    }
+
+    #[test]
+    fn test_error_retry_info() {
+        let json_data = r#"
+        {
+            "error": {
+                "code": 429,
+                "message": "Resource has been exhausted",
+                "status": "RESOURCE_EXHAUSTED",
+                "details": [
+                    {
+                        "@type": "type.googleapis.com/google.rpc.RetryInfo",
+                        "retryDelay": "17s"
+                    }
+                ]
+            }
+        }
+        "#;
+
+        let error = Error::from_response(json_data).unwrap();
+        assert_eq!(error.get_status(), "RESOURCE_EXHAUSTED");
+        assert_eq!(
+            error.get_retry_delay(),
+            Some(std::time::Duration::from_secs(17))
+        );
+    }
 }